@@ -1,10 +1,189 @@
 //! Keeps all the options, tweaks and dials of the configuration.
+use nu_protocol::{Span, Value};
+use std::{convert::TryFrom, fmt};
 
-#[derive(Debug)]
+/// the shortest `line_length` allowed, below it almost every line would have to be broken
+const MIN_LINE_LENGTH: usize = 20;
+/// the widest `indent` allowed
+const MAX_INDENT: usize = 16;
+
+#[derive(Debug, Hash)]
 pub struct Config {
     pub tab_spaces: usize,
     pub max_width: usize,
     pub margin: usize,
+    /// leave the top-level pipelines containing invalid code untouched instead of failing
+    ///
+    /// The rest of the file is still formatted, but idempotency is only guaranteed
+    /// for the parts that could be parsed.
+    pub skip_garbage: bool,
+    pub line_ending: LineEnding,
+    /// remove the byte order mark at the start of a file instead of keeping it
+    pub strip_bom: bool,
+    /// write `{ a: 1 }` instead of `{a: 1}` for the records and blocks written on a single line
+    pub space_inside_braces: bool,
+    /// sort alphabetically, and deduplicate, the names imported by `use`, `hide`, ...
+    pub sort_imports: bool,
+    /// the maximum number of blank lines kept in a row, `0` removes all of them
+    pub max_blank_lines: usize,
+    /// whether the rows of a table, and the items of a list or a record, written on several
+    /// lines end with a comma
+    ///
+    /// The last item of a list or a record written on a single line never does.
+    pub trailing_comma: TrailingComma,
+    /// the maximum number of pipelines in a closure, or any other block, written on a single line
+    ///
+    /// One by default: a block with several pipelines has one pipeline per line, a block
+    /// with a single one only goes on several lines when it would not fit in `max_width`.
+    pub closure_inline_max_elements: usize,
+    /// the case of the digits of the hexadecimal integers, e.g. `0xFF`
+    pub int_literal_case: IntLiteralCase,
+    /// align the comments at the end of consecutive lines of code in a column
+    pub align_trailing_comments: bool,
+    /// where the `|` goes in a pipeline written on several lines
+    pub pipe_placement: PipePlacement,
+    /// put the pipelines separated by a `;` on their own lines, instead of keeping them
+    /// on the same line, at the top level as well as in the blocks written on several lines
+    pub split_semicolons: bool,
+    /// write on several lines every block with more than one pipeline, or with a nested
+    /// block, list, record or subexpression, even if it would fit on a single line
+    pub always_expand_blocks: bool,
+    /// how the value of a long flag is attached to it, e.g. `--name foo` or `--name=foo`
+    pub flag_value_style: FlagValueStyle,
+    /// pad the patterns of the arms of a `match` block so that their `=>` are aligned in a
+    /// column, unless it would make a line longer than `max_width`
+    pub align_match_arms: bool,
+    /// how an empty block, closure or `else` is written, e.g. `do {}` or `do { }`
+    pub empty_block_style: EmptyBlockStyle,
+    /// whether the `:` of the fields of a record is followed by a space, e.g. `{a:1}` or
+    /// `{a: 1}`
+    pub record_colon_spacing: RecordColonSpacing,
+    /// pad the keys of a record written on several lines so that its values are aligned in
+    /// a column, unless it would make a line longer than `max_width`
+    ///
+    /// The fields after a spread item, e.g. `...$defaults`, are aligned on their own.
+    pub align_record_values: bool,
+    /// the globs of the files left out when looking for the files of a directory, e.g.
+    /// `scripts/**`
+    ///
+    /// They are matched against the paths relative to the directory of the configuration file.
+    pub excludes: Vec<String>,
+    /// the globs of the only files formatted when looking for the files of a directory,
+    /// e.g. `scripts/*`, all the Nushell files are formatted if there are none
+    ///
+    /// They are matched like [`Config::excludes`], which still leave out some of these files.
+    pub includes: Vec<String>,
+}
+
+/// the names of the choices of the options, as written in the configuration
+const LINE_ENDINGS: &[(&str, LineEnding)] = &[
+    ("auto", LineEnding::Auto),
+    ("lf", LineEnding::Lf),
+    ("crlf", LineEnding::Crlf),
+];
+const TRAILING_COMMAS: &[(&str, TrailingComma)] = &[
+    ("never", TrailingComma::Never),
+    ("always", TrailingComma::Always),
+    ("preserve", TrailingComma::Preserve),
+];
+const INT_LITERAL_CASES: &[(&str, IntLiteralCase)] = &[
+    ("preserve", IntLiteralCase::Preserve),
+    ("upper", IntLiteralCase::Upper),
+    ("lower", IntLiteralCase::Lower),
+];
+const PIPE_PLACEMENTS: &[(&str, PipePlacement)] = &[
+    ("leading", PipePlacement::Leading),
+    ("trailing", PipePlacement::Trailing),
+];
+const FLAG_VALUE_STYLES: &[(&str, FlagValueStyle)] = &[
+    ("space", FlagValueStyle::Space),
+    ("equals", FlagValueStyle::Equals),
+    ("preserve", FlagValueStyle::Preserve),
+];
+const EMPTY_BLOCK_STYLES: &[(&str, EmptyBlockStyle)] = &[
+    ("compact", EmptyBlockStyle::Compact),
+    ("spaced", EmptyBlockStyle::Spaced),
+];
+const RECORD_COLON_SPACINGS: &[(&str, RecordColonSpacing)] = &[
+    ("attached", RecordColonSpacing::Attached),
+    ("spaced", RecordColonSpacing::Spaced),
+];
+
+/// how the `:` of a record field is separated from the key and the value
+///
+/// There is never a space before the `:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordColonSpacing {
+    /// e.g. `{a:1,b:2}`
+    Attached,
+    /// e.g. `{a: 1, b: 2}`, with a space after the commas between the fields too
+    Spaced,
+}
+
+/// how an empty block is written
+///
+/// A block with only comments is not empty, it is written on several lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmptyBlockStyle {
+    /// e.g. `{}` or `{||}`
+    Compact,
+    /// e.g. `{ }` or `{|| }`
+    Spaced,
+}
+
+/// how the value of a long flag is attached to it
+///
+/// A short flag, e.g. `-n 3`, is always separated from its value by a space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlagValueStyle {
+    /// e.g. `--name foo`
+    Space,
+    /// e.g. `--name=foo`
+    Equals,
+    /// the value is attached the way it is written
+    Preserve,
+}
+
+/// where the `|` goes in a pipeline written on several lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PipePlacement {
+    /// at the start of the next line, e.g. `| lines`
+    Leading,
+    /// at the end of the line, e.g. `ls |`
+    Trailing,
+}
+
+/// the case of the letters written in a literal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IntLiteralCase {
+    /// the letters are kept as they are written
+    Preserve,
+    /// e.g. `0xFF`
+    Upper,
+    /// e.g. `0xff`
+    Lower,
+}
+
+/// whether the items of a collection written on several lines end with a comma
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrailingComma {
+    /// no comma, the newlines are enough to separate the items
+    Never,
+    /// a comma after every item
+    Always,
+    /// a comma only after the items that had one
+    Preserve,
+}
+
+/// the line ending written between the lines of the formatted code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LineEnding {
+    /// the most common line ending of the input, `\n` if it has no line ending at all
+    Auto,
+    /// always `\n`
+    Lf,
+    /// always `\r\n`
+    Crlf,
 }
 
 impl Default for Config {
@@ -13,6 +192,26 @@ impl Default for Config {
             tab_spaces: 4,
             max_width: 80,
             margin: 1,
+            skip_garbage: false,
+            line_ending: LineEnding::Auto,
+            strip_bom: false,
+            space_inside_braces: false,
+            sort_imports: false,
+            max_blank_lines: 1,
+            trailing_comma: TrailingComma::Never,
+            closure_inline_max_elements: 1,
+            int_literal_case: IntLiteralCase::Preserve,
+            align_trailing_comments: false,
+            pipe_placement: PipePlacement::Leading,
+            split_semicolons: true,
+            always_expand_blocks: false,
+            flag_value_style: FlagValueStyle::Space,
+            align_match_arms: false,
+            empty_block_style: EmptyBlockStyle::Compact,
+            record_colon_spacing: RecordColonSpacing::Spaced,
+            align_record_values: false,
+            excludes: vec![],
+            includes: vec![],
         }
     }
 }
@@ -23,6 +222,586 @@ impl Config {
             tab_spaces,
             max_width,
             margin,
+            ..Config::default()
+        }
+    }
+
+    /// start building a configuration from the default one
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// write the configuration as a NUON record, with the keys of a configuration file
+    ///
+    /// Every option is written, with a comment describing it, and reading the record back
+    /// gives the same configuration. An unlimited `closure_inline_max_elements` can't be
+    /// written, its line is then commented out.
+    pub fn to_nuon(&self) -> String {
+        let strings = |strings: &[String]| {
+            let strings: Vec<String> = strings.iter().map(|string| format!("{string:?}")).collect();
+            format!("[{}]", strings.join(", "))
+        };
+        let closure_inline_max_elements = match self.closure_inline_max_elements {
+            usize::MAX => None,
+            max => Some(max.to_string()),
+        };
+        let options = [
+            (
+                "indent",
+                "the number of spaces of an indentation level, from 1 to 16",
+                Some(self.tab_spaces.to_string()),
+            ),
+            (
+                "line_length",
+                "the maximum width of a line, at least 20",
+                Some(self.max_width.to_string()),
+            ),
+            (
+                "margin",
+                "the margin used when deciding to break a line",
+                Some(self.margin.to_string()),
+            ),
+            (
+                "skip_garbage",
+                "leave the pipelines with invalid code untouched instead of failing",
+                Some(self.skip_garbage.to_string()),
+            ),
+            (
+                "line_ending",
+                "the line ending written, \"auto\", \"lf\" or \"crlf\"",
+                Some(choice_name(LINE_ENDINGS, self.line_ending)),
+            ),
+            (
+                "strip_bom",
+                "remove the byte order mark at the start of the files",
+                Some(self.strip_bom.to_string()),
+            ),
+            (
+                "space_inside_braces",
+                "write `{ a: 1 }` instead of `{a: 1}`",
+                Some(self.space_inside_braces.to_string()),
+            ),
+            (
+                "sort_imports",
+                "sort the names imported by `use`, `hide`, ...",
+                Some(self.sort_imports.to_string()),
+            ),
+            (
+                "max_blank_lines",
+                "the maximum number of blank lines kept in a row",
+                Some(self.max_blank_lines.to_string()),
+            ),
+            (
+                "trailing_comma",
+                "a comma after the rows of a multiline table, \"never\", \"always\" or \"preserve\"",
+                Some(choice_name(TRAILING_COMMAS, self.trailing_comma)),
+            ),
+            (
+                "closure_inline_max_elements",
+                "the maximum number of pipelines of a block written on a single line",
+                closure_inline_max_elements,
+            ),
+            (
+                "int_literal_case",
+                "the case of the hexadecimal digits, \"preserve\", \"upper\" or \"lower\"",
+                Some(choice_name(INT_LITERAL_CASES, self.int_literal_case)),
+            ),
+            (
+                "align_trailing_comments",
+                "align the comments at the end of consecutive lines",
+                Some(self.align_trailing_comments.to_string()),
+            ),
+            (
+                "pipe_placement",
+                "where the `|` of a multiline pipeline goes, \"leading\" or \"trailing\"",
+                Some(choice_name(PIPE_PLACEMENTS, self.pipe_placement)),
+            ),
+            (
+                "split_semicolons",
+                "put the pipelines separated by `;` on their own lines",
+                Some(self.split_semicolons.to_string()),
+            ),
+            (
+                "always_expand_blocks",
+                "write on several lines the blocks with several pipelines or nested structures",
+                Some(self.always_expand_blocks.to_string()),
+            ),
+            (
+                "flag_value_style",
+                "how the value of a long flag is attached, \"space\", \"equals\" or \"preserve\"",
+                Some(choice_name(FLAG_VALUE_STYLES, self.flag_value_style)),
+            ),
+            (
+                "align_match_arms",
+                "align the `=>` of the arms of a `match`",
+                Some(self.align_match_arms.to_string()),
+            ),
+            (
+                "empty_block_style",
+                "how an empty block is written, \"compact\" for `{}` or \"spaced\" for `{ }`",
+                Some(choice_name(EMPTY_BLOCK_STYLES, self.empty_block_style)),
+            ),
+            (
+                "record_colon_spacing",
+                "the space after the `:` of a record, \"attached\" for `{a:1}` or \"spaced\" for `{a: 1}`",
+                Some(choice_name(RECORD_COLON_SPACINGS, self.record_colon_spacing)),
+            ),
+            (
+                "align_record_values",
+                "align the values of the records written on several lines",
+                Some(self.align_record_values.to_string()),
+            ),
+            (
+                "exclude",
+                "the globs of the files left out when formatting a directory",
+                Some(strings(&self.excludes)),
+            ),
+            (
+                "include",
+                "the globs of the only files formatted in a directory, all of them when empty",
+                Some(strings(&self.includes)),
+            ),
+        ];
+
+        let mut nuon = String::from("{\n");
+        for (key, description, value) in options {
+            nuon.push_str(&format!("    # {description}\n"));
+            match value {
+                Some(value) => nuon.push_str(&format!("    {key}: {value}\n")),
+                None => nuon.push_str(&format!("    # {key}: 1\n")),
+            }
+        }
+        nuon.push('}');
+        nuon
+    }
+}
+
+/// the name of `choice` among the `choices` of an option, quoted as a NUON string
+fn choice_name<T: PartialEq>(choices: &[(&str, T)], choice: T) -> String {
+    let name = choices
+        .iter()
+        .find(|(_, value)| *value == choice)
+        .map_or("", |(name, _)| name);
+    format!("\"{name}\"")
+}
+
+/// build a [`Config`] option by option, the options not set keep their default value
+///
+/// The setters are named after the keys of the configuration file, e.g. `line_length`.
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// the number of spaces of an indentation level
+    pub fn indent(mut self, indent: usize) -> Self {
+        self.config.tab_spaces = indent;
+        self
+    }
+
+    /// the maximum width of a line
+    pub fn line_length(mut self, line_length: usize) -> Self {
+        self.config.max_width = line_length;
+        self
+    }
+
+    /// the margin used when deciding to break a line
+    pub fn margin(mut self, margin: usize) -> Self {
+        self.config.margin = margin;
+        self
+    }
+
+    /// see [`Config::excludes`]
+    pub fn excludes(mut self, excludes: Vec<String>) -> Self {
+        self.config.excludes = excludes;
+        self
+    }
+
+    /// see [`Config::includes`]
+    pub fn includes(mut self, includes: Vec<String>) -> Self {
+        self.config.includes = includes;
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+/// the ways a configuration can be invalid
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// the configuration file could not be parsed at all
+    InvalidFormat(String),
+    /// the configuration is not a record of options
+    NotARecord,
+    /// an option that `nufmt` does not know about, with the byte offset of its value in the
+    /// configuration file when it is known
+    UnknownOption(String, Option<usize>),
+    /// an option whose value does not have the expected type
+    InvalidOptionType(String, &'static str),
+    /// an option whose value has the right type but is not allowed
+    InvalidOptionValue(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidFormat(err) => {
+                write!(f, "could not parse the configuration: {err}")
+            }
+            ConfigError::NotARecord => write!(f, "the configuration should be a record"),
+            ConfigError::UnknownOption(key, _) => write!(f, "unknown option `{key}`"),
+            ConfigError::InvalidOptionType(key, expected) => {
+                write!(f, "option `{key}` should be {expected}")
+            }
+            ConfigError::InvalidOptionValue(key, reason) => {
+                write!(f, "invalid value for option `{key}`: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// build a configuration from a record of options
+///
+/// The recognized keys are `indent`, `line_length`, `margin`, `skip_garbage`,
+/// `line_ending`, `strip_bom`, `space_inside_braces`, `sort_imports`, `max_blank_lines`,
+/// `trailing_comma`, `closure_inline_max_elements`, `int_literal_case`,
+/// `align_trailing_comments`, `pipe_placement`, `split_semicolons`, `always_expand_blocks`,
+/// `flag_value_style`, `align_match_arms`, `empty_block_style`, `record_colon_spacing`,
+/// `align_record_values`, `exclude` and `include`, all the other options keep their
+/// default value.
+impl TryFrom<Value> for Config {
+    type Error = ConfigError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let Value::Record { val: record, .. } = value else {
+            return Err(ConfigError::NotARecord);
+        };
+
+        let mut config = Config::default();
+        for (key, value) in record {
+            match key.as_str() {
+                "indent" => config.tab_spaces = parse_bounded_int(&key, &value, 1, MAX_INDENT)?,
+                "line_length" => {
+                    config.max_width =
+                        parse_bounded_int(&key, &value, MIN_LINE_LENGTH, usize::MAX)?;
+                }
+                "margin" => config.margin = parse_positive_int(&key, &value)?,
+                "skip_garbage" => config.skip_garbage = parse_bool(&key, &value)?,
+                "line_ending" => config.line_ending = parse_choice(&key, &value, LINE_ENDINGS)?,
+                "strip_bom" => config.strip_bom = parse_bool(&key, &value)?,
+                "space_inside_braces" => config.space_inside_braces = parse_bool(&key, &value)?,
+                "sort_imports" => config.sort_imports = parse_bool(&key, &value)?,
+                "max_blank_lines" => config.max_blank_lines = parse_non_negative_int(&key, &value)?,
+                "trailing_comma" => {
+                    config.trailing_comma = parse_choice(&key, &value, TRAILING_COMMAS)?;
+                }
+                "closure_inline_max_elements" => {
+                    config.closure_inline_max_elements = parse_positive_int(&key, &value)?;
+                }
+                "int_literal_case" => {
+                    config.int_literal_case = parse_choice(&key, &value, INT_LITERAL_CASES)?;
+                }
+                "align_trailing_comments" => {
+                    config.align_trailing_comments = parse_bool(&key, &value)?;
+                }
+                "pipe_placement" => {
+                    config.pipe_placement = parse_choice(&key, &value, PIPE_PLACEMENTS)?;
+                }
+                "split_semicolons" => config.split_semicolons = parse_bool(&key, &value)?,
+                "always_expand_blocks" => config.always_expand_blocks = parse_bool(&key, &value)?,
+                "align_match_arms" => config.align_match_arms = parse_bool(&key, &value)?,
+                "flag_value_style" => {
+                    config.flag_value_style = parse_choice(&key, &value, FLAG_VALUE_STYLES)?;
+                }
+                "empty_block_style" => {
+                    config.empty_block_style = parse_choice(&key, &value, EMPTY_BLOCK_STYLES)?;
+                }
+                "record_colon_spacing" => {
+                    config.record_colon_spacing =
+                        parse_choice(&key, &value, RECORD_COLON_SPACINGS)?;
+                }
+                "align_record_values" => config.align_record_values = parse_bool(&key, &value)?,
+                "exclude" => config.excludes = parse_string_list(&key, &value)?,
+                "include" => config.includes = parse_string_list(&key, &value)?,
+                _ => {
+                    let span = value.span();
+                    let offset = (span != Span::unknown()).then_some(span.start);
+                    return Err(ConfigError::UnknownOption(key, offset));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_positive_int(key: &str, value: &Value) -> Result<usize, ConfigError> {
+    let Value::Int { val, .. } = value else {
+        return Err(ConfigError::InvalidOptionType(
+            key.to_string(),
+            "a positive integer",
+        ));
+    };
+
+    match usize::try_from(*val) {
+        Ok(val) if val > 0 => Ok(val),
+        _ => Err(ConfigError::InvalidOptionValue(
+            key.to_string(),
+            format!("{val} is not a positive integer"),
+        )),
+    }
+}
+
+/// parse a positive integer between `min` and `max`, both included
+fn parse_bounded_int(
+    key: &str,
+    value: &Value,
+    min: usize,
+    max: usize,
+) -> Result<usize, ConfigError> {
+    let val = parse_positive_int(key, value)?;
+    if val < min {
+        return Err(ConfigError::InvalidOptionValue(
+            key.to_string(),
+            format!("{val} is less than the minimum of {min}"),
+        ));
+    }
+    if val > max {
+        return Err(ConfigError::InvalidOptionValue(
+            key.to_string(),
+            format!("{val} is more than the maximum of {max}"),
+        ));
+    }
+    Ok(val)
+}
+
+fn parse_non_negative_int(key: &str, value: &Value) -> Result<usize, ConfigError> {
+    let Value::Int { val, .. } = value else {
+        return Err(ConfigError::InvalidOptionType(
+            key.to_string(),
+            "a non-negative integer",
+        ));
+    };
+
+    usize::try_from(*val).map_err(|_| {
+        ConfigError::InvalidOptionValue(
+            key.to_string(),
+            format!("{val} is not a non-negative integer"),
+        )
+    })
+}
+
+fn parse_bool(key: &str, value: &Value) -> Result<bool, ConfigError> {
+    match value {
+        Value::Bool { val, .. } => Ok(*val),
+        _ => Err(ConfigError::InvalidOptionType(key.to_string(), "a boolean")),
+    }
+}
+
+fn parse_string_list(key: &str, value: &Value) -> Result<Vec<String>, ConfigError> {
+    let invalid_type = || ConfigError::InvalidOptionType(key.to_string(), "a list of strings");
+    let Value::List { vals, .. } = value else {
+        return Err(invalid_type());
+    };
+
+    vals.iter()
+        .map(|val| match val {
+            Value::String { val, .. } => Ok(val.clone()),
+            _ => Err(invalid_type()),
+        })
+        .collect()
+}
+
+/// parse an option whose value is one of the names of `choices`
+fn parse_choice<T: Copy>(
+    key: &str,
+    value: &Value,
+    choices: &[(&str, T)],
+) -> Result<T, ConfigError> {
+    let Value::String { val, .. } = value else {
+        return Err(ConfigError::InvalidOptionType(key.to_string(), "a string"));
+    };
+
+    if let Some((_, choice)) = choices.iter().find(|(name, _)| name == val) {
+        return Ok(*choice);
+    }
+
+    let names: Vec<String> = choices
+        .iter()
+        .map(|(name, _)| format!("`{name}`"))
+        .collect();
+    let names = match names.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, others)) => format!("{} or {last}", others.join(", ")),
+        None => String::new(),
+    };
+    Err(ConfigError::InvalidOptionValue(
+        key.to_string(),
+        format!("`{val}` is not one of {names}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nu_protocol::Record;
+
+    fn record(options: Vec<(&str, Value)>) -> Value {
+        let mut record = Record::new();
+        for (key, value) in options {
+            record.push(key, value);
+        }
+        Value::record(record, Span::unknown())
+    }
+
+    #[test]
+    fn read_valid_config() {
+        let config = Config::try_from(record(vec![
+            ("indent", Value::int(2, Span::unknown())),
+            ("line_length", Value::int(120, Span::unknown())),
+            ("skip_garbage", Value::bool(true, Span::unknown())),
+            ("line_ending", Value::string("crlf", Span::unknown())),
+            (
+                "closure_inline_max_elements",
+                Value::int(3, Span::unknown()),
+            ),
+            (
+                "exclude",
+                Value::list(
+                    vec![Value::string("scripts/**", Span::unknown())],
+                    Span::unknown(),
+                ),
+            ),
+            (
+                "include",
+                Value::list(
+                    vec![
+                        Value::string("scripts/*", Span::unknown()),
+                        Value::string("main.nu", Span::unknown()),
+                    ],
+                    Span::unknown(),
+                ),
+            ),
+        ]))
+        .unwrap();
+
+        assert_eq!(config.tab_spaces, 2);
+        assert_eq!(config.max_width, 120);
+        assert_eq!(config.margin, Config::default().margin);
+        assert!(config.skip_garbage);
+        assert_eq!(config.line_ending, LineEnding::Crlf);
+        assert_eq!(config.closure_inline_max_elements, 3);
+        assert_eq!(config.excludes, ["scripts/**"]);
+        assert_eq!(config.includes, ["scripts/*", "main.nu"]);
+    }
+
+    #[test]
+    fn build_config() {
+        let config = Config::builder()
+            .indent(2)
+            .line_length(100)
+            .margin(3)
+            .excludes(vec!["scripts/**".to_string()])
+            .includes(vec!["*.nu".to_string()])
+            .build();
+
+        assert_eq!(config.tab_spaces, 2);
+        assert_eq!(config.max_width, 100);
+        assert_eq!(config.margin, 3);
+        assert_eq!(config.excludes, ["scripts/**"]);
+        assert_eq!(config.includes, ["*.nu"]);
+        assert_eq!(config.line_ending, Config::default().line_ending);
+    }
+
+    #[test]
+    fn build_default_config() {
+        let config = Config::builder().build();
+        let default = Config::default();
+
+        assert_eq!(config.tab_spaces, default.tab_spaces);
+        assert_eq!(config.max_width, default.max_width);
+        assert_eq!(config.margin, default.margin);
+        assert!(config.excludes.is_empty());
+        assert!(config.includes.is_empty());
+    }
+
+    #[test]
+    fn read_invalid_config() {
+        let cases = [
+            (Value::int(2, Span::unknown()), ConfigError::NotARecord),
+            (
+                record(vec![("foo", Value::int(2, Span::unknown()))]),
+                ConfigError::UnknownOption("foo".to_string(), None),
+            ),
+            (
+                record(vec![("indent", Value::string("2", Span::unknown()))]),
+                ConfigError::InvalidOptionType("indent".to_string(), "a positive integer"),
+            ),
+            (
+                record(vec![("line_length", Value::int(-1, Span::unknown()))]),
+                ConfigError::InvalidOptionValue(
+                    "line_length".to_string(),
+                    "-1 is not a positive integer".to_string(),
+                ),
+            ),
+            (
+                record(vec![("line_length", Value::int(5, Span::unknown()))]),
+                ConfigError::InvalidOptionValue(
+                    "line_length".to_string(),
+                    "5 is less than the minimum of 20".to_string(),
+                ),
+            ),
+            (
+                record(vec![("indent", Value::int(100, Span::unknown()))]),
+                ConfigError::InvalidOptionValue(
+                    "indent".to_string(),
+                    "100 is more than the maximum of 16".to_string(),
+                ),
+            ),
+            (
+                record(vec![(
+                    "trailing_comma",
+                    Value::string("some", Span::unknown()),
+                )]),
+                ConfigError::InvalidOptionValue(
+                    "trailing_comma".to_string(),
+                    "`some` is not one of `never`, `always` or `preserve`".to_string(),
+                ),
+            ),
+            (
+                record(vec![("max_blank_lines", Value::int(-1, Span::unknown()))]),
+                ConfigError::InvalidOptionValue(
+                    "max_blank_lines".to_string(),
+                    "-1 is not a non-negative integer".to_string(),
+                ),
+            ),
+            (
+                record(vec![(
+                    "exclude",
+                    Value::list(vec![Value::int(1, Span::unknown())], Span::unknown()),
+                )]),
+                ConfigError::InvalidOptionType("exclude".to_string(), "a list of strings"),
+            ),
+            (
+                record(vec![("skip_garbage", Value::int(1, Span::unknown()))]),
+                ConfigError::InvalidOptionType("skip_garbage".to_string(), "a boolean"),
+            ),
+            (
+                record(vec![("line_ending", Value::string("cr", Span::unknown()))]),
+                ConfigError::InvalidOptionValue(
+                    "line_ending".to_string(),
+                    "`cr` is not one of `auto`, `lf` or `crlf`".to_string(),
+                ),
+            ),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(Config::try_from(value).unwrap_err(), expected);
         }
     }
 }
@@ -0,0 +1,701 @@
+//! format whole files and directories, as the `nufmt` executable does
+use crate::{config::Config, format_file_contents, format_markdown, write_file, FormatError};
+use log::info;
+use nu_glob::{MatchOptions, Pattern, PatternError};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// what [`format_paths`] does with the files it formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// write the formatted code back to the files
+    Write,
+    /// write the formatted code back to the files, after copying the original contents of
+    /// every file reformatted to `<path>.bak`, overwriting any previous copy
+    Backup,
+    /// only tell whether the files are formatted, leaving them untouched
+    Check,
+}
+
+/// what happened to a file given to, or found by, [`format_paths`]
+#[derive(Debug)]
+pub enum FileDiagnostic {
+    AlreadyFormatted,
+    Reformatted,
+    /// the file is not formatted, with its `original` contents and how they would be formatted
+    WouldReformat {
+        original: Vec<u8>,
+        formatted: Vec<u8>,
+    },
+    /// the file could not be formatted, and has been left untouched
+    ///
+    /// The `contents` are the ones of the file, empty if it could not be read, to locate
+    /// the error in them.
+    Failure {
+        contents: Vec<u8>,
+        error: FormatError,
+    },
+}
+
+/// how [`format_paths_in`] looks for the files of the directories it is given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// also look for the `.md` files, of which only the Nushell code blocks are formatted,
+    /// see [`format_markdown`]
+    pub markdown: bool,
+    /// how deep the directories are searched, `1` only keeping the files right in them,
+    /// with no limit if `None`
+    pub max_depth: Option<usize>,
+    /// follow the symbolic links, to files and to directories, instead of ignoring them
+    ///
+    /// A directory is only searched once, so a link to one of its parents is not a cycle.
+    pub follow_symlinks: bool,
+}
+
+/// format the Nushell files of `paths`, in parallel
+///
+/// The directories are searched recursively for `.nu` files, leaving out the ones not
+/// selected by [`Config::includes`] and [`Config::excludes`], matched against the paths
+/// relative to the current directory.
+/// The files given explicitly are always formatted.
+/// Returns what happened to every file, a path that does not exist being a failure.
+/// The files are sorted by path, a file found several times being only formatted once.
+pub fn format_paths(
+    paths: &[PathBuf],
+    config: &Config,
+    mode: &Mode,
+) -> Vec<(PathBuf, FileDiagnostic)> {
+    let root = std::env::current_dir().unwrap_or_default();
+    format_paths_in(&root, paths, config, mode, &SearchOptions::default())
+}
+
+/// same as [`format_paths`], with the includes and excludes relative to `root`, e.g. the
+/// directory of the configuration file, and the directories searched as told by `search`
+pub fn format_paths_in(
+    root: &Path,
+    paths: &[PathBuf],
+    config: &Config,
+    mode: &Mode,
+    search: &SearchOptions,
+) -> Vec<(PathBuf, FileDiagnostic)> {
+    let filter = match FileFilter::new(root.to_path_buf(), config, search.markdown) {
+        Ok(filter) => filter,
+        Err(err) => {
+            return paths
+                .iter()
+                .map(|path| {
+                    let error = FormatError::InvalidPattern(err.to_string());
+                    (path.clone(), failure(vec![], error))
+                })
+                .collect();
+        }
+    };
+
+    let mut files = vec![];
+    let mut unreadable = vec![];
+    for path in paths {
+        files.extend(collect_nu_files(path, &filter, search, &mut unreadable));
+    }
+    let mut diagnostics: Vec<_> = unreadable
+        .into_iter()
+        .map(|(path, err)| (path, failure(vec![], err.into())))
+        .collect();
+    // a file given explicitly, or found in two directories, is only formatted once
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.canonicalize().unwrap_or_else(|_| file.clone())));
+    files.sort();
+
+    diagnostics.par_extend(files.into_par_iter().map(|file| {
+        let diagnostic = format_path(&file, config, *mode, search.markdown);
+        (file, diagnostic)
+    }));
+    diagnostics
+}
+
+fn failure(contents: Vec<u8>, error: FormatError) -> FileDiagnostic {
+    FileDiagnostic::Failure { contents, error }
+}
+
+/// format a single file, see [`format_paths`]
+fn format_path(file: &Path, config: &Config, mode: Mode, markdown: bool) -> FileDiagnostic {
+    info!("formatting file: {:?}", file);
+    let contents = match fs::read(file) {
+        Ok(contents) => contents,
+        Err(err) => return failure(vec![], err.into()),
+    };
+    let formatted = match markdown && is_markdown_file(file) {
+        true => format_markdown(&contents, config),
+        false => format_file_contents(&contents, config),
+    };
+    let formatted = match formatted {
+        Ok(formatted) => formatted,
+        Err(err) => return failure(contents, err),
+    };
+
+    if formatted == contents {
+        return FileDiagnostic::AlreadyFormatted;
+    }
+    match mode {
+        Mode::Check => FileDiagnostic::WouldReformat {
+            original: contents,
+            formatted,
+        },
+        Mode::Write => match write_file(file, &formatted) {
+            Ok(()) => FileDiagnostic::Reformatted,
+            Err(err) => failure(contents, err.into()),
+        },
+        Mode::Backup => {
+            let mut backup = file.as_os_str().to_owned();
+            backup.push(".bak");
+            match fs::write(backup, &contents).and_then(|()| write_file(file, &formatted)) {
+                Ok(()) => FileDiagnostic::Reformatted,
+                Err(err) => failure(contents, err.into()),
+            }
+        }
+    }
+}
+
+/// which of the files found in the directories given to [`format_paths`] are formatted
+struct FileFilter {
+    /// the directory the patterns are relative to
+    root: PathBuf,
+    /// all the files are included if there are no patterns
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+    /// whether the Markdown files are selected along with the Nushell ones
+    markdown: bool,
+}
+
+impl FileFilter {
+    fn new(root: PathBuf, config: &Config, markdown: bool) -> Result<Self, PatternError> {
+        let patterns = |globs: &[String]| {
+            globs
+                .iter()
+                .map(|glob| Pattern::new(glob))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(FileFilter {
+            root,
+            includes: patterns(&config.includes)?,
+            excludes: patterns(&config.excludes)?,
+            markdown,
+        })
+    }
+
+    /// whether `path` is a file to format, matched by the patterns, see [`Self::matches`]
+    fn is_selected(&self, path: &Path) -> bool {
+        (is_nu_file(path) || self.markdown && is_markdown_file(path)) && self.matches(path)
+    }
+
+    /// whether `path` is matched by one of the includes, if any, and none of the excludes
+    ///
+    /// A pattern matching one of the parent directories of `path`, e.g. `scripts`, also
+    /// matches it.
+    /// The files outside of the root directory are only left out by the includes.
+    fn matches(&self, path: &Path) -> bool {
+        let relative = match (self.root.canonicalize(), absolute_path(path)) {
+            (Ok(root), Some(path)) => path.strip_prefix(root).ok().map(Path::to_path_buf),
+            _ => None,
+        };
+        let Some(relative) = relative else {
+            return self.includes.is_empty();
+        };
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::default()
+        };
+        let matches = |patterns: &[Pattern]| {
+            relative.ancestors().any(|path| {
+                patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path_with(path, options))
+            })
+        };
+        (self.includes.is_empty() || matches(&self.includes)) && !matches(&self.excludes)
+    }
+}
+
+/// the canonical form of `path`, which may not exist, e.g. the path given with `--stdin-filepath`
+///
+/// The part of `path` that does not exist is appended as it is to its closest existing parent.
+fn absolute_path(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut missing = vec![];
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Some(
+                missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |path, name| path.join(name)),
+            );
+        }
+        missing.push(existing.file_name()?);
+        existing = match existing.parent()? {
+            parent if parent.as_os_str().is_empty() => Path::new("."),
+            parent => parent,
+        };
+    }
+}
+
+/// whether [`Config::includes`] and [`Config::excludes`], relative to `root`, leave `path` out
+///
+/// `path` does not have to exist, e.g. it can be the path given for the code read from stdin.
+/// Nothing is left out if one of the patterns is invalid.
+pub fn is_excluded(root: &Path, path: &Path, config: &Config) -> bool {
+    FileFilter::new(root.to_path_buf(), config, false).is_ok_and(|filter| !filter.matches(path))
+}
+
+/// the files of `paths`, leaving out the directories, that [`Config::includes`] and
+/// [`Config::excludes`] would leave out if they were found in a directory
+///
+/// Those files are formatted anyway by [`format_paths_in`], as they are given explicitly,
+/// but it may not be what was meant, e.g. by a tool giving all the files of a project.
+/// The patterns are relative to `root`, and none of the files is excluded if one of them
+/// is invalid.
+pub fn excluded_files(root: &Path, paths: &[PathBuf], config: &Config) -> Vec<PathBuf> {
+    let Ok(filter) = FileFilter::new(root.to_path_buf(), config, false) else {
+        return vec![];
+    };
+    paths
+        .iter()
+        .filter(|path| path.is_file() && !filter.matches(path))
+        .cloned()
+        .collect()
+}
+
+/// expand a path, if it is a directory, into the Nushell files it contains
+///
+/// The files found in a directory are left out if `filter` does not select them, a file
+/// given explicitly is always kept.
+/// The paths that can't be read, e.g. a missing file or a broken link, are added to
+/// `unreadable` along with the error, the rest of the directory is still searched.
+fn collect_nu_files(
+    path: &Path,
+    filter: &FileFilter,
+    search: &SearchOptions,
+    unreadable: &mut Vec<(PathBuf, std::io::Error)>,
+) -> Vec<PathBuf> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            unreadable.push((path.to_path_buf(), err));
+            return vec![];
+        }
+    };
+    if !metadata.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut searched = HashSet::from([path.canonicalize().unwrap_or_else(|_| path.into())]);
+    recurse_files(path, search, 1, &mut searched, unreadable)
+        .into_iter()
+        .filter(|path| filter.is_selected(path))
+        .collect()
+}
+
+/// the files of the directory at `path`, which are `depth` levels below the one given
+///
+/// The directories already `searched`, canonicalized, are left out, and the entries that
+/// can't be read are added to `unreadable`, see [`collect_nu_files`].
+fn recurse_files(
+    path: &Path,
+    search: &SearchOptions,
+    depth: usize,
+    searched: &mut HashSet<PathBuf>,
+    unreadable: &mut Vec<(PathBuf, std::io::Error)>,
+) -> Vec<PathBuf> {
+    let mut buf = vec![];
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            unreadable.push((path.to_path_buf(), err));
+            return buf;
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                unreadable.push((path.to_path_buf(), err));
+                continue;
+            }
+        };
+        let meta = match search.follow_symlinks {
+            true => fs::metadata(entry.path()),
+            false => entry.metadata(),
+        };
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(err) => {
+                unreadable.push((entry.path(), err));
+                continue;
+            }
+        };
+
+        if meta.is_dir() && search.max_depth.is_none_or(|max_depth| depth < max_depth) {
+            match entry.path().canonicalize() {
+                Ok(canonical) => {
+                    if searched.insert(canonical) {
+                        let mut subdir =
+                            recurse_files(&entry.path(), search, depth + 1, searched, unreadable);
+                        buf.append(&mut subdir);
+                    }
+                }
+                Err(err) => unreadable.push((entry.path(), err)),
+            }
+        }
+
+        if meta.is_file() {
+            buf.push(entry.path());
+        }
+    }
+
+    buf
+}
+
+fn is_nu_file(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".nu")
+}
+
+fn is_markdown_file(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(".md")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a directory with a badly formatted file at its root, one in `scripts` and an invalid one
+    fn project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("scripts")).unwrap();
+        fs::write(dir.path().join("main.nu"), "let x  =  1\n").unwrap();
+        fs::write(dir.path().join("scripts/a.nu"), "let y  =  2\n").unwrap();
+        fs::write(dir.path().join("scripts/b.nu"), "let z = 3\n").unwrap();
+        fs::write(dir.path().join("scripts/invalid.nu"), "let = 1\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "let x  =  1\n").unwrap();
+        dir
+    }
+
+    fn statuses(mut diagnostics: Vec<(PathBuf, FileDiagnostic)>, root: &Path) -> Vec<String> {
+        diagnostics.sort_by(|a, b| a.0.cmp(&b.0));
+        diagnostics
+            .into_iter()
+            .map(|(path, diagnostic)| {
+                let status = match diagnostic {
+                    FileDiagnostic::AlreadyFormatted => "already formatted",
+                    FileDiagnostic::Reformatted => "reformatted",
+                    FileDiagnostic::WouldReformat { .. } => "would reformat",
+                    FileDiagnostic::Failure { .. } => "failure",
+                };
+                // the paths are compared with `/` between their components, whatever the platform
+                let components: Vec<_> = path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                format!("{}: {status}", components.join("/"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn format_the_nu_files_of_a_directory() {
+        let dir = project();
+        let paths = [dir.path().to_path_buf()];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Write,
+            &SearchOptions::default(),
+        );
+
+        assert_eq!(
+            statuses(diagnostics, dir.path()),
+            [
+                "main.nu: reformatted",
+                "scripts/a.nu: reformatted",
+                "scripts/b.nu: already formatted",
+                "scripts/invalid.nu: failure",
+            ]
+        );
+        let main = fs::read_to_string(dir.path().join("main.nu")).unwrap();
+        assert_eq!(main, "let x = 1\n");
+        let notes = fs::read_to_string(dir.path().join("notes.txt")).unwrap();
+        assert_eq!(notes, "let x  =  1\n");
+    }
+
+    #[test]
+    fn check_leaves_the_files_untouched() {
+        let dir = project();
+        let paths = [dir.path().join("main.nu")];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &SearchOptions::default(),
+        );
+
+        match diagnostics.as_slice() {
+            [(
+                _,
+                FileDiagnostic::WouldReformat {
+                    original,
+                    formatted,
+                },
+            )] => {
+                assert_eq!(original, b"let x  =  1\n");
+                assert_eq!(formatted, b"let x = 1\n");
+            }
+            _ => panic!("unexpected diagnostics: {:?}", diagnostics),
+        }
+        let main = fs::read_to_string(dir.path().join("main.nu")).unwrap();
+        assert_eq!(main, "let x  =  1\n");
+    }
+
+    #[test]
+    fn leave_out_the_excluded_files_of_a_directory() {
+        let dir = project();
+        let config = Config::builder()
+            .excludes(vec!["scripts/**".to_string()])
+            .build();
+        let paths = [dir.path().to_path_buf(), dir.path().join("scripts/a.nu")];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &config,
+            &Mode::Check,
+            &SearchOptions::default(),
+        );
+
+        // a file given explicitly is formatted even if it is excluded
+        assert_eq!(
+            statuses(diagnostics, dir.path()),
+            ["main.nu: would reformat", "scripts/a.nu: would reformat"]
+        );
+    }
+
+    #[test]
+    fn format_a_file_given_twice_once() {
+        let dir = project();
+        let paths = [
+            dir.path().join("scripts"),
+            dir.path().join("scripts/a.nu"),
+            dir.path().to_path_buf(),
+            dir.path().join("scripts/../main.nu"),
+        ];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &SearchOptions::default(),
+        );
+
+        let paths: Vec<&PathBuf> = diagnostics.iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            [
+                &dir.path().join("main.nu"),
+                &dir.path().join("scripts/a.nu"),
+                &dir.path().join("scripts/b.nu"),
+                &dir.path().join("scripts/invalid.nu"),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_the_nu_blocks_of_the_markdown_files() {
+        let dir = project();
+        let guide =
+            "# Guide\n\n```nu\nlet a  =  1\n```\n\ntext  \n\n```nu\nls   |  get   name\n```\n";
+        fs::write(dir.path().join("guide.md"), guide).unwrap();
+        let paths = [dir.path().to_path_buf()];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &SearchOptions::default(),
+        );
+        assert!(
+            !statuses(diagnostics, dir.path()).contains(&"guide.md: would reformat".to_string())
+        );
+
+        let search = SearchOptions {
+            markdown: true,
+            ..SearchOptions::default()
+        };
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Write,
+            &search,
+        );
+        assert!(statuses(diagnostics, dir.path()).contains(&"guide.md: reformatted".to_string()));
+        let guide = fs::read_to_string(dir.path().join("guide.md")).unwrap();
+        assert_eq!(
+            guide,
+            "# Guide\n\n```nu\nlet a = 1\n```\n\ntext  \n\n```nu\nls | get name\n```\n"
+        );
+    }
+
+    #[test]
+    fn backup_the_files_reformatted() {
+        let dir = project();
+        fs::write(dir.path().join("main.nu.bak"), "an older copy\n").unwrap();
+        let paths = [dir.path().join("main.nu"), dir.path().join("scripts/b.nu")];
+
+        format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Backup,
+            &SearchOptions::default(),
+        );
+
+        let read = |path: &str| fs::read_to_string(dir.path().join(path)).unwrap();
+        assert_eq!(read("main.nu"), "let x = 1\n");
+        assert_eq!(read("main.nu.bak"), "let x  =  1\n");
+        // a file already formatted is not written, nor backed up
+        assert!(!dir.path().join("scripts/b.nu.bak").exists());
+    }
+
+    #[test]
+    fn search_the_directories_up_to_a_depth() {
+        let dir = project();
+        let paths = [dir.path().to_path_buf()];
+        let search = SearchOptions {
+            max_depth: Some(1),
+            ..SearchOptions::default()
+        };
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &search,
+        );
+
+        assert_eq!(
+            statuses(diagnostics, dir.path()),
+            ["main.nu: would reformat"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_follow_the_symlinks_when_asked() {
+        let dir = project();
+        std::os::unix::fs::symlink(dir.path().join("scripts"), dir.path().join("linked")).unwrap();
+        // a link to a parent directory would be a cycle
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("scripts/root")).unwrap();
+        let paths = [dir.path().to_path_buf()];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &SearchOptions::default(),
+        );
+        assert_eq!(statuses(diagnostics, dir.path()).len(), 4);
+
+        let search = SearchOptions {
+            follow_symlinks: true,
+            ..SearchOptions::default()
+        };
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &search,
+        );
+        // `linked` and `scripts` are the same directory, only searched once
+        assert_eq!(statuses(diagnostics, dir.path()).len(), 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_a_broken_link_and_keep_searching() {
+        let dir = project();
+        std::os::unix::fs::symlink(
+            dir.path().join("gone.nu"),
+            dir.path().join("scripts/broken"),
+        )
+        .unwrap();
+        let paths = [dir.path().to_path_buf()];
+        let search = SearchOptions {
+            follow_symlinks: true,
+            ..SearchOptions::default()
+        };
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Check,
+            &search,
+        );
+
+        assert_eq!(
+            statuses(diagnostics, dir.path()),
+            [
+                "main.nu: would reformat",
+                "scripts/a.nu: would reformat",
+                "scripts/b.nu: already formatted",
+                "scripts/broken: failure",
+                "scripts/invalid.nu: failure",
+            ]
+        );
+    }
+
+    #[test]
+    fn tell_the_excluded_files_given_explicitly() {
+        let dir = project();
+        let config = Config::builder()
+            .excludes(vec!["scripts/**".to_string()])
+            .build();
+        let paths = [
+            dir.path().join("main.nu"),
+            dir.path().join("scripts"),
+            dir.path().join("scripts/a.nu"),
+        ];
+
+        assert_eq!(
+            excluded_files(dir.path(), &paths, &config),
+            [dir.path().join("scripts/a.nu")]
+        );
+    }
+
+    #[test]
+    fn a_missing_path_is_a_failure() {
+        let dir = project();
+        let paths = [dir.path().join("missing.nu")];
+
+        let diagnostics = format_paths_in(
+            dir.path(),
+            &paths,
+            &Config::default(),
+            &Mode::Write,
+            &SearchOptions::default(),
+        );
+
+        assert_eq!(statuses(diagnostics, dir.path()), ["missing.nu: failure"]);
+    }
+}
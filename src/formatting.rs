@@ -1,15 +1,30 @@
 //! In this module occurs most of the magic in `nufmt`.
 //!
 //! It has functions to format slice of bytes and some help functions to separate concerns while doing the job.
-use crate::config::Config;
-use log::{error, info, trace};
-use nu_parser::{flatten_block, parse, FlatShape};
+use crate::config::{
+    Config, EmptyBlockStyle, FlagValueStyle, IntLiteralCase, LineEnding, PipePlacement,
+    RecordColonSpacing, TrailingComma,
+};
+use crate::{Diagnostic, FormatError};
+use log::{info, trace};
+use nu_parser::{
+    flatten_block, flatten_expression, flatten_pipeline, lex, lex_signature, parse, FlatShape,
+    Token, TokenContents,
+};
 use nu_protocol::{
-    ast::Block,
-    engine::{EngineState, StateWorkingSet},
-    Span,
+    ast::{Argument, Block, Call, Expr, Expression, MatchPattern, Pattern, Pipeline},
+    engine::{Command, EngineState, Stack, StateWorkingSet},
+    Category, PipelineData, ShellError, Signature, Span, SyntaxShape, Value,
 };
 
+/// the comment telling `nufmt` to leave the pipeline below it untouched
+const SKIP_DIRECTIVE: &str = "# nufmt: skip";
+/// the comments disabling and enabling back the formatting of all the code between them
+const OFF_DIRECTIVE: &str = "# nufmt: off";
+const ON_DIRECTIVE: &str = "# nufmt: on";
+/// the byte order mark some editors put at the start of UTF-8 files
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
 struct DeclId;
 
 #[allow(non_upper_case_globals)]
@@ -21,35 +36,454 @@ impl DeclId {
 }
 
 fn get_engine_state() -> EngineState {
-    nu_cmd_lang::create_default_context()
+    let mut engine_state = nu_cmd_lang::create_default_context();
+    let delta = {
+        let mut working_set = StateWorkingSet::new(&engine_state);
+        working_set.add_decl(Box::new(ExportEnv));
+        for signature in filter_signatures() {
+            working_set.add_decl(Box::new(Filter(signature)));
+        }
+        working_set.render()
+    };
+    engine_state
+        .merge_delta(delta)
+        .expect("adding `export-env` to the engine state cannot fail");
+    // the parser looks for the modules of `use` and `overlay use` from `PWD`, and panics
+    // without it, but the output must not depend on the files found there: the name of this
+    // directory has a NUL byte, so no path can ever be resolved from it
+    engine_state.add_env_var(
+        "PWD".to_string(),
+        Value::string("/\0nufmt", Span::unknown()),
+    );
+    engine_state
+}
+
+/// the `export-env` keyword, which is not part of `nu-cmd-lang`
+///
+/// Without it, `export-env` would be an external command and its block would be left as it is.
+#[derive(Clone)]
+struct ExportEnv;
+
+impl Command for ExportEnv {
+    fn name(&self) -> &str {
+        "export-env"
+    }
+
+    fn usage(&self) -> &str {
+        "Run a block and preserve its environment in a current scope."
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build("export-env")
+            .required(
+                "block",
+                SyntaxShape::Block,
+                "the block to run to set the environment",
+            )
+            .category(Category::Env)
+    }
+
+    fn is_parser_keyword(&self) -> bool {
+        true
+    }
+
+    fn run(
+        &self,
+        _engine_state: &EngineState,
+        _stack: &mut Stack,
+        _call: &Call,
+        _input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        Ok(PipelineData::empty())
+    }
+}
+
+/// a filter of `nu-command`, which is not part of `nu-cmd-lang`, only known by its signature
+///
+/// Without it, the row condition of a `where` would be invalid code, and the closures of the
+/// other filters would be the arguments of an external command, left as they are.
+#[derive(Clone)]
+struct Filter(Signature);
+
+impl Command for Filter {
+    fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    fn usage(&self) -> &str {
+        &self.0.usage
+    }
+
+    fn signature(&self) -> Signature {
+        self.0.clone()
+    }
+
+    fn run(
+        &self,
+        _engine_state: &EngineState,
+        _stack: &mut Stack,
+        _call: &Call,
+        _input: PipelineData,
+    ) -> Result<PipelineData, ShellError> {
+        Ok(PipelineData::empty())
+    }
+}
+
+/// the signatures of the filters of `nu-command` taking a row condition or a closure
+fn filter_signatures() -> Vec<Signature> {
+    let closure = |parameters: Vec<SyntaxShape>| SyntaxShape::Closure(Some(parameters));
+    let filter = |name: &str, usage: &str| {
+        Signature::build(name)
+            .usage(usage)
+            .category(Category::Filters)
+    };
+    let predicate = |name: &str, usage: &str| {
+        filter(name, usage).required(
+            "predicate",
+            closure(vec![SyntaxShape::Any]),
+            "the closure called on every row",
+        )
+    };
+    vec![
+        filter("where", "Filter values based on a row condition.").required(
+            "row_condition",
+            SyntaxShape::RowCondition,
+            "the condition kept rows meet",
+        ),
+        filter("each", "Run a closure on each row of the input list.")
+            .required(
+                "closure",
+                closure(vec![SyntaxShape::Any, SyntaxShape::Int]),
+                "the closure to run",
+            )
+            .switch("keep-empty", "keep the empty results", Some('k')),
+        filter(
+            "each while",
+            "Run a closure on each row until a null is found.",
+        )
+        .required(
+            "closure",
+            closure(vec![SyntaxShape::Any, SyntaxShape::Int]),
+            "the closure to run",
+        ),
+        filter(
+            "par-each",
+            "Run a closure on each row of the input list in parallel.",
+        )
+        .required(
+            "closure",
+            closure(vec![SyntaxShape::Any, SyntaxShape::Int]),
+            "the closure to run",
+        )
+        .named(
+            "threads",
+            SyntaxShape::Int,
+            "the number of threads to use",
+            Some('t'),
+        )
+        .switch("keep-order", "keep the order of the rows", Some('k')),
+        filter("filter", "Filter values based on a predicate closure.").required(
+            "closure",
+            closure(vec![SyntaxShape::Any, SyntaxShape::Int]),
+            "the predicate to meet",
+        ),
+        filter(
+            "reduce",
+            "Aggregate a list to a single value using an accumulator closure.",
+        )
+        .required(
+            "closure",
+            closure(vec![SyntaxShape::Any, SyntaxShape::Any, SyntaxShape::Int]),
+            "the reducing function",
+        )
+        .named(
+            "fold",
+            SyntaxShape::Any,
+            "the initial value of the accumulator",
+            Some('f'),
+        ),
+        predicate(
+            "all",
+            "Test if every element of the input fulfills a predicate.",
+        ),
+        predicate(
+            "any",
+            "Test if any element of the input fulfills a predicate.",
+        ),
+        predicate(
+            "skip until",
+            "Skip elements of the input until a predicate is true.",
+        ),
+        predicate(
+            "skip while",
+            "Skip elements of the input while a predicate is true.",
+        ),
+        predicate(
+            "take until",
+            "Take elements of the input until a predicate is true.",
+        ),
+        predicate(
+            "take while",
+            "Take elements of the input while a predicate is true.",
+        ),
+    ]
 }
 
 /// format an array of bytes
 ///
 /// Reading the file gives you a list of bytes
-pub(crate) fn format_inner(contents: &[u8], _config: &Config) -> Vec<u8> {
+///
+/// The remarks about the formatting that are not errors are added to `diagnostics`.
+pub(crate) fn format_inner(
+    contents: &[u8],
+    config: &Config,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<Vec<u8>, FormatError> {
+    let (bom, without_bom) = match contents.strip_prefix(BOM) {
+        Some(rest) => (!config.strip_bom, rest),
+        None => (false, contents),
+    };
+    let (shebang, code) = split_shebang(without_bom);
+    let offset = contents.len() - code.len();
+    let mut code_diagnostics = vec![];
+    let formatted =
+        format_code(code, config, &mut code_diagnostics).map_err(|err| err.shifted_by(offset))?;
+    diagnostics.extend(
+        code_diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.shifted_by(offset)),
+    );
+
+    let formatted = match shebang {
+        Some(shebang) => prepend_shebang(shebang, code, formatted),
+        None => formatted,
+    };
+    let formatted = with_line_ending(formatted, line_ending_of(contents, config));
+
+    Ok(match bom {
+        true => [BOM, &formatted].concat(),
+        false => formatted,
+    })
+}
+
+/// format only the top-level pipelines entirely between the 1-based lines `first_line`
+/// and `last_line`, both included
+///
+/// Everything else, including the pipelines only partly in the range, is kept byte for byte.
+pub(crate) fn format_range_inner(
+    contents: &[u8],
+    config: &Config,
+    first_line: usize,
+    last_line: usize,
+) -> Result<Vec<u8>, FormatError> {
+    let code = contents.strip_prefix(BOM).unwrap_or(contents);
+    let offset = contents.len() - code.len();
+
+    let engine_state = get_engine_state();
+    let mut working_set = StateWorkingSet::new(&engine_state);
+    let parsed_block = parse(&mut working_set, None, code, false);
+
+    let in_range: Vec<Span> = parsed_block
+        .pipelines
+        .iter()
+        .filter_map(|pipeline| pipeline_span(&working_set, pipeline))
+        .filter(|span| {
+            line_of(code, span.start) >= first_line && line_of(code, span.end) <= last_line
+        })
+        .collect();
+    let (Some(first), Some(last)) = (in_range.first(), in_range.last()) else {
+        return Ok(contents.to_vec());
+    };
+
+    let (start, end) = (first.start + offset, last.end + offset);
+    let formatted = format_code(&contents[start..end], config, &mut vec![])
+        .map_err(|err| err.shifted_by(start))?;
+    let formatted = with_line_ending(formatted, line_ending_of(contents, config));
+
+    Ok([&contents[..start], &formatted, &contents[end..]].concat())
+}
+
+/// the 1-based line of the byte at `offset` in `contents`
+fn line_of(contents: &[u8], offset: usize) -> usize {
+    let before = &contents[..offset.min(contents.len())];
+    before.iter().filter(|&&byte| byte == b'\n').count() + 1
+}
+
+/// the line ending to use in the formatted version of `contents`
+pub(crate) fn line_ending_of(contents: &[u8], config: &Config) -> &'static [u8] {
+    match config.line_ending {
+        LineEnding::Lf => b"\n",
+        LineEnding::Crlf => b"\r\n",
+        LineEnding::Auto => {
+            let newlines = contents.iter().filter(|&&byte| byte == b'\n').count();
+            let crlfs = contents.windows(2).filter(|pair| pair == b"\r\n").count();
+            if crlfs > newlines - crlfs {
+                b"\r\n"
+            } else {
+                b"\n"
+            }
+        }
+    }
+}
+
+/// replace every line ending of `out` with `line_ending`
+///
+/// The code left untouched still has its original line endings, so they all
+/// have to be normalized first.
+fn with_line_ending(out: Vec<u8>, line_ending: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(out.len());
+    let mut bytes = out.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'\r' if bytes.peek() == Some(&&b'\n') => {}
+            b'\n' => result.extend(line_ending),
+            _ => result.push(byte),
+        }
+    }
+    result
+}
+
+/// format an array of bytes that doesn't contain a shebang line
+pub(crate) fn format_code(
+    contents: &[u8],
+    config: &Config,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<Vec<u8>, FormatError> {
     let engine_state = get_engine_state();
     let mut working_set = StateWorkingSet::new(&engine_state);
 
     let parsed_block = parse(&mut working_set, None, contents, false);
     trace!("parsed block:\n{:?}", &parsed_block);
 
-    if !block_has_pipelines(&parsed_block) {
+    if !block_has_code(&working_set, &parsed_block) {
         trace!("block has no pipelines!");
         info!("File has no code to format.");
-        return contents.to_vec();
+        // a file with nothing but whitespace is left empty
+        if trim_ascii_whitespace(contents).is_empty() {
+            return Ok(vec![]);
+        }
+        // nothing could be understood, which is not the same as nothing to format
+        if let Some(err) = working_set.parse_errors.first() {
+            let span = err.span();
+            if config.skip_garbage {
+                diagnostics.push(Diagnostic::new(
+                    span,
+                    "this code is left as it is because it is invalid",
+                ));
+            } else {
+                let snippet = String::from_utf8_lossy(working_set.get_span_contents(span));
+                return Err(FormatError::GarbageFound {
+                    span_start: span.start,
+                    span_end: span.end,
+                    snippet: snippet.to_string(),
+                });
+            }
+            return Ok(contents.to_vec());
+        }
+        return Ok(format_comments_only(contents, config.max_blank_lines));
     }
 
-    let flat = flatten_block(&working_set, &parsed_block);
+    let match_blocks = match_blocks(&working_set, &parsed_block);
+    let mut flat = flatten_block(&working_set, &parsed_block);
+    flat = add_match_shapes(flat, &working_set, &match_blocks);
+    flat = add_module_braces(flat, &working_set, &parsed_block);
     trace!("flattened block:\n{:?}", &flat);
 
+    let skipped_pipelines = find_skipped_pipelines(contents, &working_set, &parsed_block);
+    let disabled_pipelines = find_disabled_pipelines(contents, &working_set, &parsed_block);
+    diagnostics.extend(skipped_pipelines.iter().map(|span| {
+        Diagnostic::new(
+            *span,
+            "this pipeline is left as it is because of `# nufmt: skip`",
+        )
+    }));
+    diagnostics.extend(disabled_pipelines.iter().map(|span| {
+        Diagnostic::new(
+            *span,
+            "this code is left as it is because of `# nufmt: off`",
+        )
+    }));
+    let mut verbatim_pipelines = [skipped_pipelines, disabled_pipelines].concat();
+    if config.skip_garbage {
+        let garbage_pipelines = find_garbage_pipelines(&working_set, &parsed_block);
+        diagnostics.extend(garbage_pipelines.iter().map(|span| {
+            Diagnostic::new(
+                *span,
+                "this pipeline is left as it is because it has invalid code",
+            )
+        }));
+        verbatim_pipelines.extend(garbage_pipelines);
+    } else if let Some(span) = find_garbage(&without_pipelines(&flat, &verbatim_pipelines)) {
+        let snippet = String::from_utf8_lossy(working_set.get_span_contents(span)).to_string();
+        trace!("found garbage in {span:?}: {snippet:?}");
+        return Err(FormatError::GarbageFound {
+            span_start: span.start,
+            span_end: span.end,
+            snippet,
+        });
+    }
+    trace!("pipelines left verbatim: {:?}", &verbatim_pipelines);
+    flat = merge_verbatim_pipelines(flat, &verbatim_pipelines);
+
     let mut out: Vec<u8> = vec![];
     let mut start = 0;
     let end_of_file = contents.len();
 
     let mut after_a_def = false;
+    // how many string interpolations, e.g. `$"..."`, the current shape is in
+    let mut interpolation_depth = 0;
+    // the layout of the tables the current shape is in, the innermost one last
+    let mut tables: Vec<TableLayout> = vec![];
+    // the layout of the lists and records the current shape is in, the innermost one last
+    let mut collections: Vec<CollectionLayout> = vec![];
+    // whether the current shape is part of the import pattern of `use`, `hide`, ...
+    let mut in_import = false;
+    // the end of the shapes that have already been written
+    let mut written_until = 0;
+    // the layout of the blocks and closures the current shape is in, the innermost one last
+    let mut blocks: Vec<BlockLayout> = vec![];
+    // whether the current pipeline is an attribute, e.g. `@example`, of the item below it
+    let mut in_attribute = false;
+    // where the comments at the end of a line of code start in `out`
+    let mut trailing_comments: Vec<usize> = vec![];
+    // every pipeline, including the ones nested in blocks and closures
+    let pipelines = all_pipelines(&working_set, &parsed_block);
+    // where the top-level pipelines start, except the first one
+    let top_level_breaks: Vec<usize> = parsed_block
+        .pipelines
+        .iter()
+        .filter_map(|pipeline| pipeline_span(&working_set, pipeline))
+        .skip(1)
+        .map(|pipeline| pipeline.start)
+        .collect();
+    // the pipelines on several lines the current shape is in, with the indentation
+    // of their first line, the innermost one last
+    let mut multiline_pipelines: Vec<(Span, usize)> = vec![];
+    // where the values of the long flags start, e.g. `foo` in `--name foo`
+    let flag_values = long_flag_values(&working_set, &parsed_block);
+    // what separates the parts of the `match` arms, e.g. ` => ` before their values
+    let match_separators = match_separators(&match_blocks);
+    // where the items of the list and record patterns start, except the first ones
+    let pattern_items = pattern_items(&match_blocks);
+    // where the newlines written as they are, e.g. the ones of a multiline string, are in `out`
+    let mut kept_newlines: Vec<usize> = vec![];
+    // the calls that can have one argument per line, with where their arguments start
+    let breakable_calls = breakable_calls(contents, &working_set, &parsed_block);
+    // the calls with one argument per line the current shape is in, with the indentation
+    // of their first line and where their arguments start, the innermost one last
+    let mut multiline_calls: Vec<(Span, usize, &[usize])> = vec![];
+
+    for (index, (span, shape)) in flat.clone().into_iter().enumerate() {
+        if span.end <= written_until {
+            start = span.end;
+            continue;
+        }
+        // the implicit `$it` of a row condition, e.g. in `where size > 1`, has the span
+        // of the column read from it, which has already been written
+        if span.start < start {
+            continue;
+        }
 
-    for (span, shape) in flat.clone() {
         if span.start > start {
             trace!(
                 "Span does not start at the beginning! span {0}, start: {1}",
@@ -61,7 +495,76 @@ pub(crate) fn format_inner(contents: &[u8], _config: &Config) -> Vec<u8> {
             let printable = String::from_utf8_lossy(skipped_contents).to_string();
             trace!("contents: {:?}", printable);
 
-            out = write_only_if_have_hastag_or_equal(skipped_contents, out, true);
+            if skipped_contents.contains(&b'\n') || skipped_contents.contains(&b';') {
+                in_import = false;
+            }
+
+            let (skipped_contents, new_out) = write_cell_path_separator(skipped_contents, out);
+            let (skipped_contents, new_out) =
+                write_semicolon_before_comment(skipped_contents, new_out);
+            let (skipped_contents, new_out) = write_empty_block(skipped_contents, new_out, config);
+            out = new_out;
+            let flag_separator = match trim_ascii_whitespace(skipped_contents) {
+                separator @ (b"" | b"=") if flag_values.contains(&span.start) => Some(separator),
+                _ => None,
+            };
+            let match_separator = match_separators.iter().find(|(start, separator)| {
+                *start == span.start
+                    && trim_ascii_whitespace(skipped_contents) == trim_ascii_whitespace(separator)
+            });
+            match blocks.last() {
+                _ if match_separator.is_some() => {
+                    out = trim_trailing_spaces(out);
+                    let separator = match_separator.map_or(&b""[..], |(_, separator)| separator);
+                    if separator == b" => " {
+                        if let Some(layout) = blocks.last_mut() {
+                            layout.arrows.push(out.len());
+                        }
+                    }
+                    out.extend(separator);
+                }
+                _ if pattern_items.contains(&span.start) => {
+                    out = trim_trailing_spaces(out);
+                    match format_pattern_part(skipped_contents).as_slice() {
+                        b"" => out.push(b' '),
+                        part => out.extend(part),
+                    }
+                }
+                _ if flag_separator.is_some() => {
+                    out = trim_trailing_spaces(out);
+                    let equals = match config.flag_value_style {
+                        FlagValueStyle::Space => false,
+                        FlagValueStyle::Equals => true,
+                        FlagValueStyle::Preserve => flag_separator == Some(b"="),
+                    };
+                    out.push(if equals { b'=' } else { b' ' });
+                }
+                Some(layout) if layout.multiline && skipped_contents.contains(&b'#') => {
+                    out = layout.write_comments(
+                        skipped_contents,
+                        out,
+                        config,
+                        &mut trailing_comments,
+                    );
+                    out = layout.start_line(out, config);
+                }
+                _ => {
+                    if is_trailing_comment(skipped_contents, &out) {
+                        out = space_before_trailing_comment(out);
+                        trailing_comments.push(out.len());
+                    }
+                    out = write_only_if_have_hastag_or_equal(skipped_contents, out, true, config);
+                }
+            }
+
+            if in_attribute && skipped_contents.contains(&b'\n') {
+                // an attribute sits right on top of what it decorates
+                in_attribute = false;
+                while out.last() == Some(&b'\n') {
+                    out.pop();
+                }
+                out = insert_newline(out);
+            }
         }
 
         let mut bytes = working_set.get_span_contents(span);
@@ -69,17 +572,248 @@ pub(crate) fn format_inner(contents: &[u8], _config: &Config) -> Vec<u8> {
         trace!("shape is {shape}");
         trace!("shape contents: {:?}", &content);
 
+        if matches!(shape, FlatShape::Block | FlatShape::Closure) {
+            // the `^` of an explicit external at the start of a block is written with it
+            bytes = bytes.strip_suffix(b"^").unwrap_or(bytes);
+        }
+        // e.g. `let x = 1; let y = 2`, which can be kept on one line
+        let after_a_semicolon = {
+            let gap = &contents[start.min(span.start)..span.start];
+            gap.contains(&b';') && !gap.contains(&b'\n')
+        };
+        let keep_on_the_line = after_a_semicolon && !config.split_semicolons;
+        match blocks.last() {
+            Some(layout) if layout.breaks.contains(&span.start) => {
+                out = layout.next_pipeline(out, keep_on_the_line, config);
+            }
+            None if top_level_breaks.contains(&span.start) => {
+                out = match keep_on_the_line {
+                    true => separate_with_a_semicolon(out),
+                    false => start_new_line_if_needed(trim_trailing_spaces(out)),
+                };
+            }
+            _ => {}
+        }
+
+        while matches!(multiline_calls.last(), Some((call, _, _)) if call.end <= span.start) {
+            multiline_calls.pop();
+        }
+        if let Some((call, arguments)) = breakable_calls
+            .iter()
+            .find(|(call, _)| call.start == span.start)
+        {
+            if !fits_on_the_line(&working_set, *call, current_column(&out), config) {
+                multiline_calls.push((*call, current_indentation(&out), arguments));
+            }
+        }
+        if let Some((_, indentation, _)) = multiline_calls
+            .last()
+            .filter(|(_, _, arguments)| arguments.contains(&span.start))
+        {
+            // a call too long for its line has one argument per line
+            out = insert_newline(trim_trailing_spaces(out));
+            out.extend(vec![b' '; indentation + config.tab_spaces]);
+        }
+
+        // the layout depends on where the block starts, once the line is broken
+        if matches!(shape, FlatShape::Block | FlatShape::Closure) && opens_a_block(bytes) {
+            if let Some(layout) =
+                block_layout(&flat[index..], &working_set, &match_blocks, &out, config)
+            {
+                blocks.push(layout);
+            }
+        }
+
+        let written_from = out.len();
         match shape {
-            FlatShape::Int | FlatShape::Nothing => out.extend(bytes),
+            FlatShape::Int => out.extend(format_int(bytes, config.int_literal_case)),
+            FlatShape::Nothing => out.extend(bytes),
             FlatShape::StringInterpolation => {
+                match bytes {
+                    b"$\"" | b"$'" => interpolation_depth += 1,
+                    b"\"" | b"'" => interpolation_depth -= 1,
+                    _ => {}
+                }
                 out.extend(bytes);
             }
-            FlatShape::List | FlatShape::Record => {
-                bytes = trim_ascii_whitespace(bytes);
+            FlatShape::String if interpolation_depth > 0 => {
+                // the literal text of an interpolation is kept byte for byte
+                out.extend(bytes);
+            }
+            FlatShape::Binary => out.extend(format_binary(bytes)),
+            FlatShape::MatchPattern if is_pattern_delimiter(bytes) => {
+                // e.g. the `[` or the `]` of `[$first, ..$rest]`
+                let part = format_pattern_part(bytes);
+                if matches!(part.first(), Some(b']' | b'}')) {
+                    out = trim_trailing_spaces(out);
+                }
+                out.extend(part);
+            }
+            FlatShape::Operator if matches!(bytes, b".." | b"..<" | b"..=" | b"<..") => {
+                // a range is written without spaces, e.g. `$a..$b`, even after a variable
+                let after_a_variable = index > 0
+                    && matches!(flat[index - 1], (previous, FlatShape::Variable(_)) if previous.end == span.start);
+                if after_a_variable && out.last() == Some(&b' ') {
+                    out.pop();
+                }
                 out.extend(bytes);
             }
+            FlatShape::Table => {
+                let delimiter = TableDelimiter::from_bytes(bytes);
+                if delimiter == TableDelimiter::Open {
+                    let multiline =
+                        table_needs_multiline(&flat[index..], &working_set, &out, config);
+                    tables.push(TableLayout {
+                        multiline,
+                        indentation: current_indentation(&out),
+                    });
+                }
+                match tables.last() {
+                    Some(layout) => out.extend(layout.write(&delimiter, bytes, config)),
+                    None => out.extend(bytes),
+                }
+                if matches!(delimiter, TableDelimiter::Close { .. }) {
+                    tables.pop();
+                }
+            }
+            FlatShape::List if in_import && trim_ascii_whitespace(bytes).starts_with(b"[") => {
+                out = space_before_import_part(out);
+                out = write_delimiters(bytes, out);
+                if config.sort_imports && trim_ascii_whitespace(bytes) == b"[" {
+                    if let Some(members) = import_members(&flat[index + 1..], &working_set) {
+                        written_until = members.end;
+                        out.extend(members.sorted());
+                    }
+                }
+            }
+            FlatShape::List | FlatShape::Record if !in_import => {
+                let code = delimiter_code(bytes);
+                if opens_a_collection(&code) {
+                    collections.push(CollectionLayout {
+                        multiline: collection_needs_multiline(
+                            &flat[index..],
+                            &working_set,
+                            &out,
+                            config,
+                        ),
+                        indentation: current_indentation(&out),
+                        values: vec![vec![]],
+                    });
+                }
+                match collections.last() {
+                    Some(layout) if layout.multiline && code != b":" => {
+                        out = layout.write(bytes, out, config);
+                    }
+                    _ if shape == FlatShape::List => {
+                        out = write_delimiters(without_trailing_comma(bytes, &code), out);
+                    }
+                    _ => {
+                        out = write_delimiters(without_trailing_comma(bytes, &code), out);
+                        // e.g. `{a: 1, b: 2}`, the fields are spaced like their values
+                        if matches!(&code[..], b":" | b",")
+                            && config.record_colon_spacing == RecordColonSpacing::Spaced
+                        {
+                            out.push(b' ');
+                        }
+                        if config.space_inside_braces {
+                            out = space_inside_brace(trim_ascii_whitespace(bytes), out);
+                        }
+                    }
+                }
+                if let Some(layout) = collections
+                    .last_mut()
+                    .filter(|layout| config.align_record_values && layout.multiline)
+                {
+                    if code == b":" {
+                        if let Some(values) = layout.values.last_mut() {
+                            values.push(out.len());
+                        }
+                    } else if index + 1 < flat.len() {
+                        // e.g. the `...$defaults` of a record, the fields after it are
+                        // aligned on their own
+                        let next = Span::new(span.end, flat[index + 1].0.end);
+                        let next = trim_ascii_whitespace(working_set.get_span_contents(next));
+                        if next.starts_with(b"...") {
+                            layout.values.push(vec![]);
+                        }
+                    }
+                }
+                if closes_a_collection(&code) {
+                    if let Some(layout) = collections.pop() {
+                        for values in layout.values.iter().rev() {
+                            out = align_in_a_column(
+                                out,
+                                values,
+                                config.max_width,
+                                &mut trailing_comments,
+                                &mut kept_newlines,
+                            );
+                        }
+                    }
+                }
+            }
+            FlatShape::List | FlatShape::Record => out = write_delimiters(bytes, out),
+            FlatShape::Block | FlatShape::Closure if matches!(blocks.last(), Some(layout) if layout.multiline && layout.span.start == span.start) =>
+            {
+                // the comments right after the `{` are part of the delimiter
+                let comment = bytes
+                    .iter()
+                    .position(|&byte| byte == b'#')
+                    .unwrap_or(bytes.len());
+                let delimiter = trim_ascii_whitespace(&bytes[..comment]);
+                out.extend(attach_closure_parameters(delimiter));
+                if let Some(layout) = blocks.last() {
+                    let delimiter_end = bytes[..comment]
+                        .iter()
+                        .rposition(|byte| !byte.is_ascii_whitespace())
+                        .map_or(0, |end| end + 1);
+                    out = layout.write_comments(
+                        &bytes[delimiter_end..],
+                        out,
+                        config,
+                        &mut trailing_comments,
+                    );
+                    out = layout.start_line(out, config);
+                }
+            }
+            FlatShape::Block | FlatShape::Closure if matches!(blocks.last(), Some(layout) if layout.multiline && layout.span.end == span.end) => {
+                if let Some(layout) = blocks.pop() {
+                    out = layout.close(bytes, out, config, &mut trailing_comments);
+                    if config.align_match_arms {
+                        out = align_in_a_column(
+                            out,
+                            &layout.arrows,
+                            config.max_width,
+                            &mut trailing_comments,
+                            &mut kept_newlines,
+                        );
+                    }
+                }
+            }
             FlatShape::Block | FlatShape::Closure => {
+                if blocks.last().map(|layout| layout.span.end) == Some(span.end) {
+                    blocks.pop();
+                }
+                if closes_a_block(bytes) {
+                    let (rest, new_out) = write_empty_block(bytes, out, config);
+                    (bytes, out) = (rest, new_out);
+                }
                 bytes = trim_ascii_whitespace(bytes);
+                if matches!(bytes, b")" | b"}") && out.last() == Some(&b' ') {
+                    out.pop();
+                }
+                let bytes = attach_closure_parameters(bytes);
+                out.extend(&bytes);
+                // e.g. `{|x| $x + 1}`, the body is spaced from the parameters
+                if bytes.starts_with(b"{|") && bytes.ends_with(b"|") {
+                    out.push(b' ');
+                }
+                if config.space_inside_braces {
+                    out = space_inside_brace(&bytes, out);
+                }
+            }
+            FlatShape::String | FlatShape::Keyword if in_import => {
+                out = space_before_import_part(out);
                 out.extend(bytes);
             }
             FlatShape::String => {
@@ -90,31 +824,126 @@ pub(crate) fn format_inner(contents: &[u8], _config: &Config) -> Vec<u8> {
                 }
             }
             FlatShape::Pipe => {
-                out.extend(b"| ");
+                in_import = false;
+                // the `e>` of `e>|` is left out of the pipe's span
+                let pipe = [redirection_before_pipe(&contents[..span.start]), b"|"].concat();
+                while matches!(multiline_pipelines.last(), Some((pipeline, _)) if pipeline.end <= span.start)
+                {
+                    multiline_pipelines.pop();
+                }
+                let pipeline = innermost_pipeline(&pipelines, span);
+                let indentation = match (multiline_pipelines.last(), pipeline) {
+                    (Some((multiline, indentation)), Some(pipeline)) if *multiline == pipeline => {
+                        Some(*indentation)
+                    }
+                    (_, Some(pipeline)) => {
+                        let indentation = current_indentation(&out);
+                        if fits_on_the_line(&working_set, pipeline, indentation, config) {
+                            None
+                        } else {
+                            multiline_pipelines.push((pipeline, indentation));
+                            Some(indentation)
+                        }
+                    }
+                    (_, None) => None,
+                };
+                if let Some(indentation) = indentation {
+                    // a pipeline too long for its line has one command per line
+                    let indentation = vec![b' '; indentation + config.tab_spaces];
+                    out = trim_trailing_spaces(out);
+                    match config.pipe_placement {
+                        PipePlacement::Leading => {
+                            out = start_new_line_if_needed(out);
+                            out.extend(indentation);
+                            out.extend(&pipe);
+                            out.extend(b" ");
+                        }
+                        PipePlacement::Trailing => {
+                            out = space(out);
+                            out.extend(&pipe);
+                            out = insert_newline(out);
+                            out.extend(indentation);
+                        }
+                    }
+                } else {
+                    if !matches!(out.last(), None | Some(b' ') | Some(b'\n')) {
+                        out.extend(b" ");
+                    }
+                    out.extend(&pipe);
+                    out.extend(b" ");
+                }
             }
             FlatShape::InternalCall(declid) => {
                 trace!("Called Internal call with {declid}");
+                if is_exported_const(&contents[..span.start], bytes) {
+                    // the span of `export const` leaves out the `export`
+                    out = start_new_line_if_needed(out);
+                    out.extend(b"export ");
+                }
                 out = resolve_call(bytes, declid, out);
                 after_a_def = declid == DeclId::Def;
+                in_import = matches!(
+                    bytes,
+                    b"use" | b"export use" | b"hide" | b"overlay use" | b"overlay hide"
+                );
+            }
+            FlatShape::External => {
+                in_import = false;
+                // the `^` of an explicit external, e.g. `^git`, is not part of the span
+                let has_caret = span.start > 0 && contents[span.start - 1] == b'^';
+                in_attribute = bytes.starts_with(b"@");
+                out = resolve_external(bytes, has_caret, out);
+            }
+            FlatShape::Signature => {
+                let signature = format_signature(bytes, current_column(&out), config);
+                if signature.ends_with(b"]") {
+                    // the body of the command has to start on the same line as its signature
+                    out.extend(signature);
+                    // `extern` has no body to start
+                    if matches!(
+                        flat.get(index + 1),
+                        Some((_, FlatShape::Block | FlatShape::Closure))
+                    ) {
+                        out.extend(b" ");
+                    }
+                } else {
+                    // a body glued to the signature, e.g. `[]{ ... }`, is part of the span
+                    out.extend(signature);
+                    out = insert_newline(out);
+                }
             }
-            FlatShape::External => out = resolve_external(bytes, out),
-            FlatShape::ExternalArg | FlatShape::Signature | FlatShape::Keyword => {
+            FlatShape::ExternalArg => {
+                out = space(out);
                 out.extend(bytes);
-                out = insert_newline(out);
             }
-            FlatShape::VarDecl(varid) | FlatShape::Variable(varid) => {
-                trace!("Called variable or vardecl with {varid}");
+            FlatShape::Keyword if bytes == b"=" => {
+                // the `=` of `const` and `alias`
+                out = space(out);
                 out.extend(bytes);
-                out.extend(b" ");
+                out.push(b' ');
             }
             FlatShape::Garbage => {
-                error!("found garbage 😢 {content}");
+                // only whole pipelines to be left untouched are flagged as garbage at this point
+                out = start_new_line_if_needed(out);
                 out.extend(bytes);
                 out = insert_newline(out);
             }
-
+            FlatShape::VarDecl(varid) | FlatShape::Variable(varid) => {
+                trace!("Called variable or vardecl with {varid}");
+                out.extend(bytes);
+                out.extend(b" ");
+            }
             _ => out.extend(bytes),
         }
+        if is_written_as_it_is(&shape) && bytes.contains(&b'\n') {
+            if let Some(at) = out[written_from..]
+                .windows(bytes.len())
+                .rposition(|window| window == bytes)
+            {
+                let at = written_from + at;
+                kept_newlines.extend((at..at + bytes.len()).filter(|&index| out[index] == b'\n'));
+            }
+        }
 
         if is_last_span(span, &flat) && span.end < end_of_file {
             trace!(
@@ -127,15 +956,245 @@ pub(crate) fn format_inner(contents: &[u8], _config: &Config) -> Vec<u8> {
             let printable = String::from_utf8_lossy(remaining_contents).to_string();
             trace!("contents: {:?}", printable);
 
-            out = write_only_if_have_hastag_or_equal(remaining_contents, out, false);
+            let (remaining_contents, new_out) = write_cell_path_separator(remaining_contents, out);
+            let (remaining_contents, new_out) =
+                write_semicolon_before_comment(remaining_contents, new_out);
+            let (remaining_contents, new_out) =
+                write_empty_block(remaining_contents, new_out, config);
+            out = new_out;
+            if is_trailing_comment(remaining_contents, &out) {
+                out = space_before_trailing_comment(out);
+                trailing_comments.push(out.len());
+            }
+            out = write_only_if_have_hastag_or_equal(remaining_contents, out, false, config);
+        }
+
+        start = span.end;
+    }
+
+    out = trim_trailing_whitespace(out, &kept_newlines, &mut trailing_comments);
+    if config.align_trailing_comments {
+        out = align_trailing_comments(out, &trailing_comments, config.max_width);
+    }
+    Ok(out)
+}
+
+/// whether the shape is written byte for byte, newlines included, e.g. a string
+fn is_written_as_it_is(shape: &FlatShape) -> bool {
+    matches!(
+        shape,
+        FlatShape::String
+            | FlatShape::StringInterpolation
+            | FlatShape::ExternalArg
+            | FlatShape::Filepath
+            | FlatShape::GlobPattern
+            | FlatShape::Garbage
+    )
+}
+
+/// remove the spaces and tabs at the end of every line of `out`
+///
+/// This is the last pass over the formatted code, catching any space left at the end
+/// of a line. The lines ending with one of the sorted `kept_newlines`, e.g. inside a multiline
+/// string or some code left untouched, are kept as they are, and the `trailing_comments`
+/// are moved along with the code.
+fn trim_trailing_whitespace(
+    out: Vec<u8>,
+    kept_newlines: &[usize],
+    trailing_comments: &mut [usize],
+) -> Vec<u8> {
+    // where the lines have been trimmed in `out`, with how many bytes were removed
+    let mut removed: Vec<(usize, usize)> = vec![];
+    let mut trim_line = |trimmed: &mut Vec<u8>, end: usize| {
+        let carriage_return = trimmed.last() == Some(&b'\r');
+        if carriage_return {
+            trimmed.pop();
+        }
+        let length = trimmed.len();
+        while matches!(trimmed.last(), Some(b' ' | b'\t')) {
+            trimmed.pop();
+        }
+        removed.push((end, length - trimmed.len()));
+        if carriage_return {
+            trimmed.push(b'\r');
+        }
+    };
+
+    let mut trimmed = Vec::with_capacity(out.len());
+    for (index, &byte) in out.iter().enumerate() {
+        if byte == b'\n' && kept_newlines.binary_search(&index).is_err() {
+            trim_line(&mut trimmed, index);
         }
+        trimmed.push(byte);
+    }
+    trim_line(&mut trimmed, out.len());
+
+    for comment in trailing_comments.iter_mut() {
+        *comment -= removed
+            .iter()
+            .filter(|(end, _)| end <= comment)
+            .map(|(_, count)| count)
+            .sum::<usize>();
+    }
+    trimmed
+}
+
+/// separate the `#!` interpreter line from the rest of the contents
+///
+/// The shebang has to stay the very first line of the file, so it must never be
+/// seen by the formatter as a regular comment that could be moved around.
+fn split_shebang(contents: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    if !contents.starts_with(b"#!") {
+        return (None, contents);
+    }
+
+    match contents.iter().position(|&byte| byte == b'\n') {
+        Some(end_of_line) => (Some(&contents[..end_of_line]), &contents[end_of_line + 1..]),
+        None => (Some(contents), &[]),
+    }
+}
+
+/// put back the shebang verbatim as the first line of the formatted output
+///
+/// The shebang is separated from the code by a single blank line, unless a comment,
+/// e.g. the documentation of the script, was right below it in `code`.
+fn prepend_shebang(shebang: &[u8], code: &[u8], formatted: Vec<u8>) -> Vec<u8> {
+    let mut out = trim_ascii_whitespace(shebang).to_vec();
+    let first_line = code.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    let attached_comment = trim_ascii_whitespace(first_line).starts_with(b"#");
+    let formatted = match formatted.iter().position(|&byte| byte != b'\n') {
+        Some(start) => &formatted[start..],
+        None => return out,
+    };
+    out = insert_newline(out);
+    if !attached_comment {
+        out = insert_newline(out);
+    }
+    out.extend(formatted);
+    out
+}
+
+/// normalize the spacing inside the `[...]` signature of a custom command
+///
+/// The parameters are lexed the same way `nu-parser` does, so the names, types and
+/// default values are kept exactly as written: only the whitespace around them changes.
+/// A signature written on several lines keeps its lines, and their comments, with each
+/// line indented once.
+/// A signature written on a single line is broken into one parameter per line when it
+/// would not fit in the line, knowing it starts at `column`.
+fn format_signature(bytes: &[u8], column: usize, config: &Config) -> Vec<u8> {
+    let Some(inner) = bytes
+        .strip_prefix(b"[")
+        .and_then(|bytes| bytes.strip_suffix(b"]"))
+    else {
+        return bytes.to_vec();
+    };
 
-        start = span.end + 1;
+    let (tokens, err) = lex_signature(inner, 0, b"\n\r", b":=,", false);
+    if err.is_some() {
+        trace!("could not lex the signature, leaving it as is");
+        return bytes.to_vec();
     }
 
+    let lines = if inner.contains(&b'\n') {
+        split_signature_lines(inner, &tokens)
+    } else {
+        let mut line = vec![];
+        for token in &tokens {
+            push_signature_token(&mut line, token, inner);
+        }
+
+        if column + line.len() + 2 <= config.max_width {
+            let mut out = b"[".to_vec();
+            out.extend(line);
+            out.extend(b"]");
+            return out;
+        }
+        split_signature_parameters(inner, &tokens)
+    };
+
+    let mut out = b"[".to_vec();
+    for line in lines.iter().filter(|line| !line.is_empty()) {
+        out = insert_newline(out);
+        out.extend(b" ".repeat(config.tab_spaces));
+        out.extend(line);
+    }
+    out = insert_newline(out);
+    out.extend(b"]");
     out
 }
 
+/// write a token of a signature at the end of a `line`, with a single space before it
+///
+/// `:` and `,` are glued to the parameter before them.
+fn push_signature_token(line: &mut Vec<u8>, token: &Token, inner: &[u8]) {
+    let mut content = &inner[token.span.start..token.span.end];
+    if token.contents == TokenContents::Comment {
+        content = trim_ascii_whitespace(content);
+    }
+    match (token.contents, content) {
+        (TokenContents::Item, b":" | b",") => {}
+        _ if !line.is_empty() => line.push(b' '),
+        _ => {}
+    }
+    line.extend(content);
+}
+
+/// split the tokens of a signature into the lines they were written on
+fn split_signature_lines(inner: &[u8], tokens: &[Token]) -> Vec<Vec<u8>> {
+    let mut lines: Vec<Vec<u8>> = vec![vec![]];
+    let mut previous_end = 0;
+
+    for token in tokens {
+        let starts_a_line = inner[previous_end..token.span.start].contains(&b'\n');
+        previous_end = token.span.end;
+
+        if starts_a_line && !lines[lines.len() - 1].is_empty() {
+            lines.push(vec![]);
+        }
+        let line = lines.last_mut().expect("there is always at least one line");
+        push_signature_token(line, token, inner);
+    }
+
+    lines
+}
+
+/// split the tokens of a signature into its parameters, without the `,` separating them
+///
+/// A parameter is a name, optionally followed by a `: type` and a `= default` value.
+fn split_signature_parameters(inner: &[u8], tokens: &[Token]) -> Vec<Vec<u8>> {
+    let mut parameters: Vec<Vec<u8>> = vec![];
+    let mut expects_value = false;
+
+    for token in tokens {
+        let content = &inner[token.span.start..token.span.end];
+        match content {
+            b"," => {
+                expects_value = false;
+                continue;
+            }
+            b":" | b"=" => expects_value = true,
+            _ if expects_value => expects_value = false,
+            _ => parameters.push(vec![]),
+        }
+
+        match parameters.last_mut() {
+            Some(parameter) => push_signature_token(parameter, token, inner),
+            None => parameters.push(content.to_vec()),
+        }
+    }
+
+    parameters
+}
+
+/// return the number of bytes already written on the last line of `out`
+fn current_column(out: &[u8]) -> usize {
+    match out.iter().rposition(|&byte| byte == b'\n') {
+        Some(newline) => out.len() - newline - 1,
+        None => out.len(),
+    }
+}
+
 /// insert a newline at the end of a buffer
 fn insert_newline(mut bytes: Vec<u8>) -> Vec<u8> {
     bytes.extend(b"\n");
@@ -154,88 +1213,1750 @@ fn insert_newline(mut bytes: Vec<u8>) -> Vec<u8> {
 /// `out` + \n + `bytes`
 ///
 /// That's what `bytes_before_content` bool is for
+///
+/// The blank lines found in `bytes` are kept, up to `max_blank_lines` in a row.
 fn write_only_if_have_hastag_or_equal(
     bytes: &[u8],
     mut out: Vec<u8>,
     bytes_before_content: bool,
+    config: &Config,
 ) -> Vec<u8> {
-    if bytes.contains(&b'#') {
+    let max_blank_lines = config.max_blank_lines;
+    if let Some(comment_start) = bytes.iter().position(|&byte| byte == b'#') {
         trace!("This have a comment. Writing.");
+        let blank_lines_before = count_blank_lines(&bytes[..comment_start]);
         if bytes_before_content {
-            out.extend(trim_ascii_whitespace(bytes));
+            // a comment on its own line must not be glued to the code before it
+            if bytes[..comment_start].contains(&b'\n') {
+                out = start_new_line_if_needed(out);
+                out = insert_blank_lines(out, blank_lines_before.min(max_blank_lines));
+            }
+            out.extend(align_comment_block(bytes, max_blank_lines));
             out = insert_newline(out);
+            // the blank lines between a comment and its code separate them on purpose
+            out = insert_blank_lines(out, blank_lines_after_comments(bytes).min(max_blank_lines));
+        } else if is_trailing_comment(bytes, &out) {
+            out.extend(align_comment_block(bytes, max_blank_lines));
         } else {
             out = insert_newline(out);
-            out.extend(trim_ascii_whitespace(bytes));
+            out = insert_blank_lines(out, blank_lines_before.min(max_blank_lines));
+            out.extend(align_comment_block(bytes, max_blank_lines));
         }
     } else if bytes.contains(&b'=') {
         out.extend(trim_ascii_whitespace(bytes));
         out.extend(b" ");
     } else {
         trace!("The contents doesn't have a '#'. Skipping.");
+        let blank_lines = count_blank_lines(bytes).min(max_blank_lines);
+        if bytes_before_content && blank_lines > 0 {
+            out = start_new_line_if_needed(out);
+            out = insert_blank_lines(out, blank_lines);
+        } else if bytes_before_content && !bytes.contains(&b'\n') {
+            let code = trim_ascii_whitespace(bytes);
+            if code.is_empty() && !bytes.is_empty() {
+                out = space(out);
+            }
+        }
     }
     out
 }
 
-#[allow(clippy::wildcard_in_or_patterns)]
-fn resolve_call(c_bytes: &[u8], declid: usize, mut out: Vec<u8>) -> Vec<u8> {
-    out = match declid {
-        DeclId::If => insert_newline(out),
-        DeclId::Let => insert_newline(out),
-        DeclId::Def => insert_newline(out),
-        DeclId::ExportDefEnv | _ => out,
-    };
-    out.extend(c_bytes);
-    out.extend(b" ");
-    out
+/// write the separator of the members of a cell path at the start of `bytes`, if any, and
+/// return what comes after it
+///
+/// The separator, e.g. the `?.` of `$r.a?.b`, is attached to the member before it, as is
+/// the `?` of an optional last member, e.g. in `$r.a? 2`, which is kept whatever follows.
+fn write_cell_path_separator(bytes: &[u8], mut out: Vec<u8>) -> (&[u8], Vec<u8>) {
+    let length = bytes
+        .iter()
+        .take_while(|&&byte| matches!(byte, b'.' | b'?'))
+        .count();
+    if length == 0 {
+        return (bytes, out);
+    }
+    out = trim_trailing_spaces(out);
+    out.extend(&bytes[..length]);
+    (&bytes[length..], out)
 }
 
-fn resolve_external(c_bytes: &[u8], mut out: Vec<u8>) -> Vec<u8> {
-    out = match c_bytes {
-        [b'c', b'd'] => insert_newline(out),
-        _ => out,
+/// write the `;` ending a line of code before its comment, if any, and return what comes
+/// after it
+///
+/// The `;` is attached to the code, e.g. `let a = 1; # comment`, the comment is then
+/// written as any other trailing comment.
+fn write_semicolon_before_comment(bytes: &[u8], mut out: Vec<u8>) -> (&[u8], Vec<u8>) {
+    let Some(semicolon) = bytes.iter().position(|&byte| !byte.is_ascii_whitespace()) else {
+        return (bytes, out);
     };
-    out.extend(c_bytes);
-    out.extend(b" ");
-    out
-}
-
-/// make sure there is a newline at the end of a buffer
-pub(crate) fn add_newline_at_end_of_file(out: Vec<u8>) -> Vec<u8> {
-    match out.last() {
-        Some(&b'\n') => out,
-        _ => insert_newline(out),
+    let rest = &bytes[semicolon + 1..];
+    let rest_of_line = rest.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    if bytes[semicolon] != b';'
+        || bytes[..semicolon].contains(&b'\n')
+        || trim_ascii_whitespace(rest_of_line).first() != Some(&b'#')
+    {
+        return (bytes, out);
     }
+    out = trim_trailing_spaces(out);
+    out.push(b';');
+    (rest, out)
 }
 
-/// strip all spaces, new lines and tabs found a sequence of bytes
+/// write the empty block at the start of `bytes`, if any, and return what comes after it
 ///
-/// Because you don't know how the incoming code is formatted,
-/// the best way to format is to strip all the whitespace
-/// and afterwards include the new lines and indentation correctly
-/// according to the configuration
-fn trim_ascii_whitespace(x: &[u8]) -> &[u8] {
-    let Some(from) = x.iter().position(|x| !x.is_ascii_whitespace()) else {
-        return &x[0..0];
+/// An empty block, e.g. the `{}` of `do {}`, has no shape of its own, so it would be
+/// lost with the whitespace around it otherwise.
+/// A block with only comments has no shape either, its comments are written on their own
+/// lines, one level deeper than the line of the block.
+fn write_empty_block<'a>(
+    bytes: &'a [u8],
+    mut out: Vec<u8>,
+    config: &Config,
+) -> (&'a [u8], Vec<u8>) {
+    // e.g. the `= {||}` of `let f = {||}`
+    let after_equals = trim_ascii_whitespace(bytes)
+        .strip_prefix(b"=")
+        .map(|_| &bytes[bytes.iter().position(|&byte| byte == b'=').unwrap_or(0) + 1..]);
+    let Some((params, inner, rest)) = split_empty_block(after_equals.unwrap_or(bytes)) else {
+        return (bytes, out);
     };
-    let to = x.iter().rposition(|x| !x.is_ascii_whitespace()).unwrap();
-    let result = &x[from..=to];
-    let printable = String::from_utf8_lossy(result).to_string();
-    trace!("stripped the whitespace, result: {:?}", printable);
-    result
+
+    if after_equals.is_some() {
+        out = space(out);
+        out.push(b'=');
+    }
+    out = space(out);
+    let indentation = current_indentation(&out);
+    out.push(b'{');
+    out.extend(params);
+    let mut lines = inner
+        .split(|&byte| byte == b'\n')
+        .map(trim_ascii_whitespace);
+    if let Some(first) = lines.next().filter(|line| !line.is_empty()) {
+        // a comment right after the `{` stays on its line
+        out.push(b' ');
+        out.extend(first);
+    }
+    for comment in lines.filter(|line| !line.is_empty()) {
+        out = insert_newline(out);
+        out.extend(vec![b' '; indentation + config.tab_spaces]);
+        out.extend(comment);
+    }
+    if inner.contains(&b'#') {
+        out = insert_newline(out);
+        out.extend(vec![b' '; indentation]);
+    } else if config.empty_block_style == EmptyBlockStyle::Spaced {
+        out.push(b' ');
+    }
+    out.push(b'}');
+    (rest, out)
 }
 
-/// return true if the Nushell block has at least 1 pipeline
+/// split the empty block, e.g. `{ }` or `{||}`, at the start of `bytes` from the rest
 ///
-/// This function exists because sometimes is passed to `nufmt` an empty String,
-/// or a nu code which the parser can't identify something runnable
+/// The block is returned as its parameters, e.g. `||`, what is inside its braces, only
+/// whitespace and comments, and what comes after it.
+fn split_empty_block(bytes: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let open = bytes.iter().position(|byte| !byte.is_ascii_whitespace())?;
+    if bytes[open] != b'{' {
+        return None;
+    }
+    let after_open = &bytes[open + 1..];
+    let params_start = after_open
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())?;
+    let params = match after_open[params_start..].strip_prefix(b"|") {
+        Some(params) => {
+            let end = params.iter().position(|&byte| byte == b'|')?;
+            &after_open[params_start..params_start + end + 2]
+        }
+        None => &after_open[..0],
+    };
+    let inner_start = match params {
+        b"" => 0,
+        _ => params_start + params.len(),
+    };
+
+    // only whitespace and comments, which run to the end of their line, can be inside
+    let mut index = inner_start;
+    while let Some(&byte) = after_open.get(index) {
+        match byte {
+            b'}' => {
+                let inner = &after_open[inner_start..index];
+                return Some((params, inner, &after_open[index + 1..]));
+            }
+            b'#' => {
+                index += after_open[index..].iter().position(|&byte| byte == b'\n')?;
+            }
+            byte if byte.is_ascii_whitespace() => index += 1,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// add a single space at the end of `out`, to separate what comes next from what is there
+///
+/// No space is added at the start of a line, after another space or right after an
+/// opening `(`, `[` or `{`.
+fn space(mut out: Vec<u8>) -> Vec<u8> {
+    if !matches!(
+        out.last(),
+        None | Some(b' ') | Some(b'\n') | Some(b'(') | Some(b'[') | Some(b'{')
+    ) {
+        out.push(b' ');
+    }
+    out
+}
+
+/// whether `bytes` start with a comment on the same line as the code at the end of `out`
+fn is_trailing_comment(bytes: &[u8], out: &[u8]) -> bool {
+    let Some(comment_start) = bytes.iter().position(|&byte| byte == b'#') else {
+        return false;
+    };
+    !bytes[..comment_start].contains(&b'\n') && !matches!(out.last(), None | Some(b'\n'))
+}
+
+/// separate the code at the end of `out` from the comment after it with a single space
+fn space_before_trailing_comment(out: Vec<u8>) -> Vec<u8> {
+    let mut out = trim_trailing_spaces(out);
+    out.push(b' ');
+    out
+}
+
+/// pad the code of consecutive lines ending with a comment so that their comments are aligned
+///
+/// `comments` are where these comments start in `out`.
+/// A run of lines is left alone if aligning it would make a line longer than `max_width`.
+fn align_trailing_comments(out: Vec<u8>, comments: &[usize], max_width: usize) -> Vec<u8> {
+    // the line, the column and the width of every comment
+    let positions: Vec<(usize, usize, usize)> = comments
+        .iter()
+        .map(|&start| {
+            let line = out[..start].iter().filter(|&&byte| byte == b'\n').count();
+            let width = out[start..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .unwrap_or(out.len() - start);
+            (line, current_column(&out[..start]), width)
+        })
+        .collect();
+
+    let mut padding = vec![0; comments.len()];
+    let mut run_start = 0;
+    while run_start < positions.len() {
+        let mut run_end = run_start + 1;
+        while run_end < positions.len() && positions[run_end].0 == positions[run_end - 1].0 + 1 {
+            run_end += 1;
+        }
+
+        let run = &positions[run_start..run_end];
+        let column = run.iter().map(|&(_, column, _)| column).max().unwrap_or(0);
+        if run.iter().all(|&(_, _, width)| column + width <= max_width) {
+            for (index, &(_, comment_column, _)) in run.iter().enumerate() {
+                padding[run_start + index] = column - comment_column;
+            }
+        }
+        run_start = run_end;
+    }
+
+    let mut aligned = Vec::with_capacity(out.len());
+    let mut written = 0;
+    for (&start, &spaces) in comments.iter().zip(&padding) {
+        aligned.extend(&out[written..start]);
+        aligned.extend(vec![b' '; spaces]);
+        written = start;
+    }
+    aligned.extend(&out[written..]);
+    aligned
+}
+
+/// pad the lines of `out` so that what follows each of the `starts` is aligned in a column
+///
+/// `starts` are positions in `out`, one per line, e.g. where the ` => ` of the arms of a
+/// `match` block start.
+/// The lines are left alone if aligning them would make one longer than `max_width`,
+/// and the `trailing_comments` and `kept_newlines` after them are moved along with the code.
+fn align_in_a_column(
+    mut out: Vec<u8>,
+    starts: &[usize],
+    max_width: usize,
+    trailing_comments: &mut [usize],
+    kept_newlines: &mut [usize],
+) -> Vec<u8> {
+    // the column and the width of what follows every start
+    let positions: Vec<(usize, usize)> = starts
+        .iter()
+        .map(|&start| {
+            let width = out[start..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .unwrap_or(out.len() - start);
+            (current_column(&out[..start]), width)
+        })
+        .collect();
+    let column = positions
+        .iter()
+        .map(|&(column, _)| column)
+        .max()
+        .unwrap_or(0);
+    if positions
+        .iter()
+        .any(|&(_, width)| column + width > max_width)
+    {
+        return out;
+    }
+
+    for (&start, &(start_column, _)) in starts.iter().zip(&positions).rev() {
+        let spaces = column - start_column;
+        out.splice(start..start, vec![b' '; spaces]);
+        for position in trailing_comments
+            .iter_mut()
+            .chain(kept_newlines.iter_mut())
+            .filter(|position| **position >= start)
+        {
+            *position += spaces;
+        }
+    }
+    out
+}
+
+/// the number of blank lines in a run of whitespace
+fn count_blank_lines(whitespace: &[u8]) -> usize {
+    let newlines = whitespace.iter().filter(|&&byte| byte == b'\n').count();
+    newlines.saturating_sub(1)
+}
+
+/// insert `count` blank lines after a line of `out`
+///
+/// No blank line is ever put at the very start of the output.
+fn insert_blank_lines(mut out: Vec<u8>, count: usize) -> Vec<u8> {
+    if !out.is_empty() {
+        out.extend(vec![b'\n'; count]);
+    }
+    out
+}
+
+/// write the delimiters of a list or a record, i.e. what is between its items
+///
+/// The whitespace is removed, but the comments are kept after the item they follow,
+/// with a newline after them so that the next item is not commented out.
+/// Items only separated by whitespace are kept apart by a single space.
+fn write_delimiters(bytes: &[u8], mut out: Vec<u8>) -> Vec<u8> {
+    // some items, like variables, are written with a space after them
+    let closes_an_item = match trim_ascii_whitespace(bytes).first() {
+        None => true,
+        Some(first) => matches!(first, b']' | b'}' | b',' | b':'),
+    };
+    if closes_an_item && out.last() == Some(&b' ') {
+        out.pop();
+    }
+
+    if trim_ascii_whitespace(bytes).is_empty() {
+        out.extend(b" ");
+        return out;
+    }
+
+    for line in bytes.split(|&byte| byte == b'\n') {
+        let (code, comment) = match line.iter().position(|&byte| byte == b'#') {
+            Some(comment_start) => line.split_at(comment_start),
+            None => (line, &[][..]),
+        };
+        out.extend(trim_ascii_whitespace(code));
+
+        if !comment.is_empty() {
+            if !matches!(out.last(), None | Some(b'\n')) {
+                out.extend(b" ");
+            }
+            out.extend(trim_ascii_whitespace(comment));
+            out = insert_newline(out);
+        }
+    }
+    out
+}
+
+/// strip the indentation of every line of a block of comments
+///
+/// The lines of a block of comments are kept together, and all of them end up at
+/// the indentation of the code they precede, instead of keeping their own.
+fn align_comment_block(bytes: &[u8], max_blank_lines: usize) -> Vec<u8> {
+    let mut lines: Vec<&[u8]> = vec![];
+    let mut blank_lines = 0;
+    for line in trim_ascii_whitespace(bytes).split(|&byte| byte == b'\n') {
+        let line = trim_ascii_whitespace(line);
+        blank_lines = if line.is_empty() { blank_lines + 1 } else { 0 };
+        if blank_lines <= max_blank_lines {
+            lines.push(line);
+        }
+    }
+    lines.join(&b'\n')
+}
+
+/// format a file with nothing but comments, keeping every comment where it is
+///
+/// Only the whitespace at the end of the lines goes, along with the blank lines at the end
+/// of the file and the ones above `max_blank_lines` in a row.
+fn format_comments_only(contents: &[u8], max_blank_lines: usize) -> Vec<u8> {
+    let mut lines: Vec<&[u8]> = vec![];
+    let mut blank_lines = 0;
+    for line in contents.split(|&byte| byte == b'\n') {
+        let line = line.trim_ascii_end();
+        blank_lines = if line.is_empty() { blank_lines + 1 } else { 0 };
+        if blank_lines <= max_blank_lines {
+            lines.push(line);
+        }
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines.join(&b'\n')
+}
+
+/// the number of blank lines between the last comment of `bytes` and its end
+fn blank_lines_after_comments(bytes: &[u8]) -> usize {
+    match bytes.iter().rposition(|&byte| byte == b'#') {
+        Some(last_comment) => count_blank_lines(&bytes[last_comment..]),
+        None => 0,
+    }
+}
+
+/// start a new line, unless the output is empty or already at the start of a line
+///
+/// This makes sure the output never begins with a blank line, and that a comment
+/// stays attached to the code right below it.
+fn start_new_line_if_needed(out: Vec<u8>) -> Vec<u8> {
+    match out.last() {
+        None | Some(b'\n') => out,
+        Some(_) => insert_newline(out),
+    }
+}
+
+#[allow(clippy::wildcard_in_or_patterns)]
+fn resolve_call(c_bytes: &[u8], declid: usize, mut out: Vec<u8>) -> Vec<u8> {
+    out = match declid {
+        DeclId::If => start_new_line_if_needed(out),
+        DeclId::Let => start_new_line_if_needed(out),
+        DeclId::Def => start_new_line_if_needed(out),
+        DeclId::ExportDefEnv | _ => out,
+    };
+    // the words of a call such as `export def` are separated by a single space
+    let words: Vec<&[u8]> = c_bytes
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect();
+    out.extend(words.join(&b' '));
+    out.extend(b" ");
+    out
+}
+
+/// whether the `const` in `bytes` is the end of an `export const` started in `before`
+fn is_exported_const(before: &[u8], bytes: &[u8]) -> bool {
+    bytes == b"const"
+        && before
+            .split(|byte| byte.is_ascii_whitespace() || *byte == b';')
+            .rfind(|word| !word.is_empty())
+            == Some(b"export")
+}
+
+fn redirection_before_pipe(before: &[u8]) -> &[u8] {
+    let word = before
+        .split(|byte| byte.is_ascii_whitespace())
+        .next_back()
+        .unwrap_or_default();
+    match word {
+        b"e>" | b"err>" | b"o+e>" | b"e+o>" | b"out+err>" | b"err+out>" => word,
+        _ => b"",
+    }
+}
+
+fn resolve_external(c_bytes: &[u8], has_caret: bool, mut out: Vec<u8>) -> Vec<u8> {
+    out = match c_bytes {
+        [b'c', b'd'] | [b'@', ..] => start_new_line_if_needed(out),
+        _ => out,
+    };
+    if has_caret {
+        out.push(b'^');
+    }
+    out.extend(c_bytes);
+    out
+}
+
+/// the delimiters found between the cells of a table, e.g. `[[a b]; [1 2]]`
+#[derive(Debug, PartialEq)]
+enum TableDelimiter {
+    /// the `[[` opening the table and its header
+    Open,
+    /// the `]; [` between the header and the first row
+    HeaderEnd,
+    /// the `] [` between two rows, possibly with a comma
+    RowSeparator { comma: bool },
+    /// the whitespace, or comma, between two cells of the same row
+    CellSeparator { comma: bool },
+    /// the `]]` closing the last row and the table, possibly with a comma after the last row
+    Close { comma: bool },
+    /// anything else, e.g. a delimiter with a comment, which is kept as it is
+    Other,
+}
+
+impl TableDelimiter {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.contains(&b'#') {
+            return TableDelimiter::Other;
+        }
+
+        let structure: Vec<u8> = bytes
+            .iter()
+            .copied()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+        match &structure[..] {
+            b"[[" => TableDelimiter::Open,
+            b"];[" => TableDelimiter::HeaderEnd,
+            b"][" => TableDelimiter::RowSeparator { comma: false },
+            b"],[" => TableDelimiter::RowSeparator { comma: true },
+            b"" => TableDelimiter::CellSeparator { comma: false },
+            b"," => TableDelimiter::CellSeparator { comma: true },
+            b"]]" => TableDelimiter::Close { comma: false },
+            b"],]" => TableDelimiter::Close { comma: true },
+            _ => TableDelimiter::Other,
+        }
+    }
+}
+
+/// how a table is written
+struct TableLayout {
+    /// whether the header and every row are on their own line
+    multiline: bool,
+    /// the indentation of the line the table starts on
+    indentation: usize,
+}
+
+impl TableLayout {
+    fn write(&self, delimiter: &TableDelimiter, bytes: &[u8], config: &Config) -> Vec<u8> {
+        let row_start = [
+            b"\n".to_vec(),
+            vec![b' '; self.indentation + config.tab_spaces],
+        ]
+        .concat();
+        let row_end = |comma: bool| match (config.trailing_comma, comma) {
+            (TrailingComma::Always, _) | (TrailingComma::Preserve, true) => b"],".to_vec(),
+            _ => b"]".to_vec(),
+        };
+        match (delimiter, self.multiline) {
+            (TableDelimiter::Open, true) => [&b"["[..], &row_start, b"["].concat(),
+            (TableDelimiter::Open, false) => b"[[".to_vec(),
+            (TableDelimiter::HeaderEnd, true) => [&b"];"[..], &row_start, b"["].concat(),
+            (TableDelimiter::HeaderEnd, false) => b"]; [".to_vec(),
+            (TableDelimiter::RowSeparator { comma }, true) => {
+                [&row_end(*comma)[..], &row_start, b"["].concat()
+            }
+            (TableDelimiter::RowSeparator { .. }, false) => b"] [".to_vec(),
+            (TableDelimiter::CellSeparator { comma: true }, _) => b", ".to_vec(),
+            (TableDelimiter::CellSeparator { comma: false }, _) => b" ".to_vec(),
+            (TableDelimiter::Close { comma }, true) => {
+                let indentation = vec![b' '; self.indentation];
+                [&row_end(*comma)[..], b"\n", &indentation, b"]"].concat()
+            }
+            (TableDelimiter::Close { .. }, false) => b"]]".to_vec(),
+            (TableDelimiter::Other, _) => bytes.to_vec(),
+        }
+    }
+}
+
+/// whether the table opened by the first shape of `flat` should have one row per line
+///
+/// That is the case when it has more than one row, when one of the tables nested in it
+/// has, or when it would not fit on the current line of `out`.
+/// A nested table with one row per line can't be kept on a single line of its container,
+/// deciding from the inside out gives the same layout from one formatting to the next.
+fn table_needs_multiline(
+    flat: &[(Span, FlatShape)],
+    working_set: &StateWorkingSet,
+    out: &[u8],
+    config: &Config,
+) -> bool {
+    let Some((open, _)) = flat.first() else {
+        return false;
+    };
+
+    // the number of rows of the tables opened, the outermost one first
+    let mut rows: Vec<usize> = vec![];
+    let mut nested_multiline = false;
+    for (span, shape) in flat {
+        if *shape != FlatShape::Table {
+            continue;
+        }
+        match TableDelimiter::from_bytes(working_set.get_span_contents(*span)) {
+            TableDelimiter::Open => rows.push(1),
+            TableDelimiter::RowSeparator { .. } => {
+                if let Some(count) = rows.last_mut() {
+                    *count += 1;
+                }
+            }
+            TableDelimiter::Close { .. } => {
+                let table_rows = rows.pop().unwrap_or(1);
+                if !rows.is_empty() {
+                    nested_multiline |= table_rows > 1;
+                    continue;
+                }
+                let table = working_set.get_span_contents(Span::new(open.start, span.end));
+                let width = table
+                    .split(|byte| byte.is_ascii_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(|word| word.len() + 1)
+                    .sum::<usize>();
+                return table_rows > 1
+                    || nested_multiline
+                    || current_column(out) + width > config.max_width;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// the code of a delimiter of a list or a record, without its whitespace and comments
+fn delimiter_code(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .split(|&byte| byte == b'\n')
+        .flat_map(|line| {
+            let comment = line
+                .iter()
+                .position(|&byte| byte == b'#')
+                .unwrap_or(line.len());
+            line[..comment].iter().copied()
+        })
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect()
+}
+
+/// whether the delimiter `code` opens a list or a record, e.g. `[`
+fn opens_a_collection(code: &[u8]) -> bool {
+    matches!(code.first(), Some(b'[' | b'{'))
+}
+
+/// whether the delimiter `code` closes a list or a record, e.g. `]`
+fn closes_a_collection(code: &[u8]) -> bool {
+    matches!(code.last(), Some(b']' | b'}'))
+}
+
+/// the delimiter `bytes` of a collection written on a single line, without the comma
+/// before its closing delimiter, e.g. `}` for `,}`
+fn without_trailing_comma<'a>(bytes: &'a [u8], code: &[u8]) -> &'a [u8] {
+    if opens_a_collection(code) || !closes_a_collection(code) {
+        return bytes;
+    }
+    let closing = code[code.len() - 1];
+    bytes
+        .iter()
+        .rposition(|&byte| byte == closing)
+        .map_or(bytes, |position| &bytes[position..])
+}
+
+/// whether the list or the record opened by the first shape of `flat` has one item per line
+///
+/// That is the case when it has comments between its items, when one of the lists,
+/// records or tables nested in it is written on several lines whatever its width, or
+/// when it would not fit on the current line of `out`.
+/// Like for the tables, deciding from the inside out gives the same layout from one
+/// formatting to the next: a nested collection on several lines can't be kept on a single
+/// line of its container.
+fn collection_needs_multiline(
+    flat: &[(Span, FlatShape)],
+    working_set: &StateWorkingSet,
+    out: &[u8],
+    config: &Config,
+) -> bool {
+    let mut depth = 0;
+    for (span, shape) in flat {
+        let bytes = working_set.get_span_contents(*span);
+        match shape {
+            FlatShape::List | FlatShape::Record => {
+                let code = delimiter_code(bytes);
+                if opens_a_collection(&code) {
+                    depth += 1;
+                }
+                if bytes.contains(&b'#') {
+                    return true;
+                }
+                if closes_a_collection(&code) {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            // a table with several rows, or with comments, is always on several lines
+            FlatShape::Table
+                if matches!(
+                    TableDelimiter::from_bytes(bytes),
+                    TableDelimiter::RowSeparator { .. } | TableDelimiter::Other
+                ) =>
+            {
+                return true;
+            }
+            _ => {}
+        }
+    }
+    current_column(out) + collection_width(flat, working_set, config) > config.max_width
+}
+
+/// the width of the list or the record opened by the first shape of `flat`, written on
+/// a single line
+///
+/// It is computed from the shapes rather than from the source, so that it is the same
+/// whether the collection is on a single line or on several: a separator between two
+/// items counts as `, ` in a record and as a single space in a list, whether it is a comma
+/// or a newline, and the whitespace between the other shapes as a single space.
+fn collection_width(
+    flat: &[(Span, FlatShape)],
+    working_set: &StateWorkingSet,
+    config: &Config,
+) -> usize {
+    let spaced = usize::from(config.record_colon_spacing == RecordColonSpacing::Spaced);
+    let mut width = 0;
+    let mut depth = 0;
+    let mut previous_end = None;
+    for (span, shape) in flat {
+        if let Some(previous_end) = previous_end {
+            let gap = working_set.get_span_contents(Span::new(previous_end, span.start));
+            let code = trim_ascii_whitespace(gap);
+            width += code.len() + usize::from(code.len() < gap.len());
+        }
+        previous_end = Some(span.end);
+
+        let bytes = working_set.get_span_contents(*span);
+        if !matches!(shape, FlatShape::List | FlatShape::Record) {
+            width += trim_ascii_whitespace(bytes).len();
+            continue;
+        }
+        let code = delimiter_code(bytes);
+        width += match &code[..] {
+            b":" => 1 + spaced,
+            b"" | b"," if *shape == FlatShape::Record => 1 + spaced,
+            b"" | b"," => 1,
+            _ if opens_a_collection(&code) => code.len(),
+            // the comma before a closing delimiter is left out
+            _ if closes_a_collection(&code) => 1,
+            _ => code.len(),
+        };
+        if opens_a_collection(&code) {
+            depth += 1;
+        }
+        if closes_a_collection(&code) {
+            depth -= 1;
+            if depth == 0 {
+                break;
+            }
+        }
+    }
+    width
+}
+
+/// how a list or a record is written
+struct CollectionLayout {
+    /// whether every item is on its own line
+    multiline: bool,
+    /// the indentation of the line the collection starts on
+    indentation: usize,
+    /// where the values of the fields of a multiline record start in `out`, when they are
+    /// aligned, in groups separated by the spread items
+    values: Vec<Vec<usize>>,
+}
+
+impl CollectionLayout {
+    /// write a delimiter, other than the `:` of a record, of a collection with one item per line
+    ///
+    /// The comments after an item stay on its line, the ones on their own line are
+    /// indented like the items.
+    /// Each item ends with a comma depending on `trailing_comma`.
+    fn write(&self, bytes: &[u8], out: Vec<u8>, config: &Config) -> Vec<u8> {
+        let item_indentation = vec![b' '; self.indentation + config.tab_spaces];
+        let code = delimiter_code(bytes);
+        let mut out = out;
+        if opens_a_collection(&code) {
+            out.push(code[0]);
+        } else {
+            out = trim_trailing_spaces(out);
+            let comma = code.first() == Some(&b',');
+            if matches!(
+                (config.trailing_comma, comma),
+                (TrailingComma::Always, _) | (TrailingComma::Preserve, true)
+            ) {
+                out.push(b',');
+            }
+        }
+        let lines: Vec<&[u8]> = bytes.split(|&byte| byte == b'\n').collect();
+        let mut blank_lines = 0;
+        for (number, line) in lines.iter().enumerate() {
+            let Some(comment_start) = line.iter().position(|&byte| byte == b'#') else {
+                // the last line is the one of the next item
+                let blank = line.iter().all(u8::is_ascii_whitespace);
+                blank_lines += usize::from(blank && number > 0 && number < lines.len() - 1);
+                continue;
+            };
+            if number == 0 {
+                out.push(b' ');
+            } else {
+                out = insert_newline(out);
+                out = self.insert_blank_lines(out, blank_lines, config);
+                out.extend(&item_indentation);
+            }
+            out.extend(trim_ascii_whitespace(&line[comment_start..]));
+            blank_lines = 0;
+        }
+        out = insert_newline(out);
+        match code.last() {
+            Some(&closing) if closes_a_collection(&code) => {
+                out.extend(vec![b' '; self.indentation]);
+                out.push(closing);
+            }
+            _ => {
+                out = self.insert_blank_lines(out, blank_lines, config);
+                out.extend(item_indentation);
+            }
+        }
+        out
+    }
+
+    /// keep the blank lines between two items, up to `max_blank_lines`, but not the ones
+    /// right after the opening delimiter
+    fn insert_blank_lines(&self, out: Vec<u8>, blank_lines: usize, config: &Config) -> Vec<u8> {
+        if out.ends_with(b"[\n") || out.ends_with(b"{\n") {
+            return out;
+        }
+        insert_blank_lines(out, blank_lines.min(config.max_blank_lines))
+    }
+}
+
+/// how the pipelines of a block, or of a closure, are written
+struct BlockLayout {
+    /// the whole block, from `{` to `}`
+    span: Span,
+    /// whether every pipeline is on its own line, otherwise they are separated by `; `
+    multiline: bool,
+    /// the indentation of the line the block starts on
+    indentation: usize,
+    /// where the ` => ` of the arms of a `match` block have been written in `out`
+    arrows: Vec<usize>,
+    /// where the pipelines of the block start, except the first one
+    breaks: Vec<usize>,
+}
+
+impl BlockLayout {
+    /// separate the pipeline about to be written from the previous one
+    ///
+    /// In a multiline block, the pipeline goes on a new line, unless it should be kept
+    /// on the line of the previous one.
+    fn next_pipeline(&self, out: Vec<u8>, keep_on_the_line: bool, config: &Config) -> Vec<u8> {
+        if self.multiline && !keep_on_the_line {
+            return self.start_line(out, config);
+        }
+        separate_with_a_semicolon(out)
+    }
+
+    /// start a new line of the block at the end of `out`
+    fn start_line(&self, mut out: Vec<u8>, config: &Config) -> Vec<u8> {
+        out = start_new_line_if_needed(trim_trailing_spaces(out));
+        out.extend(vec![b' '; self.indentation + config.tab_spaces]);
+        out
+    }
+
+    /// write the last delimiter of a multiline block, e.g. ` }` or the ` )` of a
+    /// subexpression, on its own line
+    fn close(
+        &self,
+        bytes: &[u8],
+        mut out: Vec<u8>,
+        config: &Config,
+        trailing_comments: &mut Vec<usize>,
+    ) -> Vec<u8> {
+        // e.g. the comments after the last pipeline
+        let brace = bytes.iter().rposition(|&byte| matches!(byte, b'}' | b')'));
+        // e.g. the `{}` of a `do {}` ending the block, which has no shape of its own
+        let (before_brace, new_out) =
+            write_empty_block(&bytes[..brace.unwrap_or(bytes.len())], out, config);
+        out = new_out;
+        out = self.write_comments(before_brace, out, config, trailing_comments);
+        out = start_new_line_if_needed(trim_trailing_spaces(out));
+        out.extend(vec![b' '; self.indentation]);
+        out.push(brace.map_or(b'}', |brace| bytes[brace]));
+        out
+    }
+
+    /// write the comments of `bytes`, found between the pipelines of the block
+    ///
+    /// A comment on the line of the code before it stays there, the other ones are
+    /// indented on their own lines.
+    fn write_comments(
+        &self,
+        bytes: &[u8],
+        mut out: Vec<u8>,
+        config: &Config,
+        trailing_comments: &mut Vec<usize>,
+    ) -> Vec<u8> {
+        let mut blank_lines = 0;
+        for (index, line) in bytes.split(|&byte| byte == b'\n').enumerate() {
+            let line = trim_ascii_whitespace(line);
+            if line.is_empty() {
+                blank_lines += usize::from(index > 0);
+                continue;
+            }
+            if index == 0 {
+                out = space_before_trailing_comment(out);
+                trailing_comments.push(out.len());
+            } else {
+                out = start_new_line_if_needed(trim_trailing_spaces(out));
+                if !out.ends_with(b"{\n") {
+                    out = insert_blank_lines(out, blank_lines.min(config.max_blank_lines));
+                }
+                out.extend(vec![b' '; self.indentation + config.tab_spaces]);
+            }
+            out.extend(line);
+            blank_lines = 0;
+        }
+        out
+    }
+}
+
+/// end the pipeline at the end of `out` with `; `, for another one to follow on the same line
+fn separate_with_a_semicolon(out: Vec<u8>) -> Vec<u8> {
+    let mut out = trim_trailing_spaces(out);
+    out.extend(b"; ");
+    out
+}
+
+/// whether `bytes` are the delimiter opening a block, or a closure and its parameters, e.g. `{|x| `,
+/// or a subexpression
+fn opens_a_block(bytes: &[u8]) -> bool {
+    let bytes = trim_ascii_whitespace(bytes);
+    (bytes.starts_with(b"{") && !bytes.ends_with(b"}"))
+        || (bytes.starts_with(b"(") && !bytes.ends_with(b")"))
+}
+
+/// the `{` opening a closure, followed by its parameters without any whitespace before
+/// them, e.g. `{|x|` for `{ |x|`, other delimiters are left as they are
+fn attach_closure_parameters(delimiter: &[u8]) -> Vec<u8> {
+    match delimiter.strip_prefix(b"{") {
+        Some(rest) if trim_ascii_whitespace(rest).starts_with(b"|") => {
+            [&b"{"[..], trim_ascii_whitespace(rest)].concat()
+        }
+        _ => delimiter.to_vec(),
+    }
+}
+
+/// whether `bytes` are the delimiter closing a block, a closure or a subexpression
+fn closes_a_block(bytes: &[u8]) -> bool {
+    matches!(trim_ascii_whitespace(bytes).last(), Some(b'}' | b')'))
+}
+
+/// the layout of the block, or closure, opened by the first shape of `flat`
+///
+/// Returns `None` if the block can't be found, it is then written as it is.
+fn block_layout(
+    flat: &[(Span, FlatShape)],
+    working_set: &StateWorkingSet,
+    match_blocks: &[MatchBlock],
+    out: &[u8],
+    config: &Config,
+) -> Option<BlockLayout> {
+    let (open, _) = flat.first()?;
+
+    let mut depth = 0;
+    let mut close = None;
+    for (span, shape) in flat {
+        if !matches!(shape, FlatShape::Block | FlatShape::Closure) {
+            continue;
+        }
+        let bytes = working_set.get_span_contents(*span);
+        if opens_a_block(bytes) {
+            depth += 1;
+        } else if closes_a_block(bytes) {
+            depth -= 1;
+            if depth == 0 {
+                close = Some(*span);
+                break;
+            }
+        }
+    }
+    let span = Span::new(open.start, close?.end);
+
+    // the arms of a `match` are laid out like pipelines, always one per line
+    if let Some(match_block) = match_blocks.iter().find(|block| block.span == span) {
+        return Some(BlockLayout {
+            span,
+            multiline: true,
+            indentation: current_indentation(out),
+            arrows: vec![],
+            breaks: match_block
+                .arms
+                .iter()
+                .skip(1)
+                .map(|(pattern, _)| pattern.span.start)
+                .collect(),
+        });
+    }
+
+    // the body is parsed before the blocks it is part of, e.g. the value of a `let`,
+    // and the block of a `module` leaves out its braces
+    let inner = Span::new(span.start + 1, span.end - 1);
+    let block = (working_set.permanent_state.num_blocks()..working_set.num_blocks())
+        .map(|block_id| working_set.get_block(block_id))
+        .find(|block| block.span == Some(span) || block.span == Some(inner))?;
+    let starts: Vec<usize> = block
+        .pipelines
+        .iter()
+        .filter_map(|pipeline| pipeline_span(working_set, pipeline))
+        .map(|pipeline| pipeline.start)
+        .collect();
+
+    Some(BlockLayout {
+        span,
+        multiline: !should_inline_block(working_set, span, starts.len(), match_blocks, out, config),
+        indentation: current_indentation(out),
+        arrows: vec![],
+        breaks: starts.into_iter().skip(1).collect(),
+    })
+}
+
+/// a `match` block, e.g. the `{ 1 => "one", _ => "many" }` of `match $x { ... }`
+struct MatchBlock {
+    /// the whole block, from `{` to `}`
+    span: Span,
+    /// the pattern and the value of each arm
+    arms: Vec<(MatchPattern, Expression)>,
+}
+
+/// the top-level `block` and all the blocks nested in it
+///
+/// The blocks of the module files the parser may have read are left out, they are not
+/// part of the code being formatted and their spans are outside of it.
+fn parsed_blocks<'a>(
+    working_set: &'a StateWorkingSet,
+    block: &'a Block,
+) -> impl Iterator<Item = &'a Block> {
+    let input_end = block.span.map_or(usize::MAX, |span| span.end);
+    (working_set.permanent_state.num_blocks()..working_set.num_blocks())
+        .map(move |block_id| working_set.get_block(block_id))
+        .filter(move |nested| nested.span.is_none_or(|span| span.end <= input_end))
+        .chain(std::iter::once(block))
+}
+
+/// all the `match` blocks of the top-level `block`, nested ones included
+fn match_blocks(working_set: &StateWorkingSet, block: &Block) -> Vec<MatchBlock> {
+    let mut match_blocks: Vec<MatchBlock> = parsed_blocks(working_set, block)
+        .flat_map(|block| &block.pipelines)
+        .flat_map(|pipeline| &pipeline.elements)
+        .filter_map(|element| match &element.expression().expr {
+            Expr::Call(call) => Some(call),
+            _ => None,
+        })
+        .flat_map(|call| &call.arguments)
+        .filter_map(|argument| match argument {
+            Argument::Positional(Expression {
+                expr: Expr::MatchBlock(arms),
+                span,
+                ..
+            }) if !arms.is_empty() => Some(MatchBlock {
+                span: *span,
+                arms: arms.clone(),
+            }),
+            _ => None,
+        })
+        .collect();
+    // the value of a `let` is found both in its own block and in the call
+    match_blocks.sort_by_key(|block| block.span.start);
+    match_blocks.dedup_by_key(|block| block.span);
+    match_blocks
+}
+
+/// add the shapes `flatten_block` leaves out of the `match_blocks`
+///
+/// The braces of a `match` block become block shapes, to be laid out like the ones
+/// of any other block, and the guards of its arms, e.g. `$x > 0` in `$x if $x > 0 => 1`,
+/// are formatted like any other expression.
+fn add_match_shapes(
+    mut flat: Vec<(Span, FlatShape)>,
+    working_set: &StateWorkingSet,
+    match_blocks: &[MatchBlock],
+) -> Vec<(Span, FlatShape)> {
+    for match_block in match_blocks {
+        let span = match_block.span;
+        let (first, last) = match (match_block.arms.first(), match_block.arms.last()) {
+            (Some((first, _)), Some((_, last))) => (first.span, last.span),
+            _ => continue,
+        };
+        // a `,` after the last arm is left out, like the ones between the arms
+        let after_last = working_set.get_span_contents(Span::new(last.end, span.end));
+        let close = match after_last
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+        {
+            Some(comma) if after_last[comma] == b',' => last.end + comma + 1,
+            _ => last.end,
+        };
+        // the shapes of the blocks ending right after the match block, e.g. the value of
+        // a `let` or the body of a command, start with what ends the match block
+        flat.retain(|(shape, _)| !(last.end <= shape.start && shape.end == span.end));
+        for (shape, _) in &mut flat {
+            if last.end <= shape.start && shape.start < span.end && span.end < shape.end {
+                shape.start = span.end;
+            }
+        }
+        flat.push((Span::new(span.start, first.start), FlatShape::Block));
+        flat.push((Span::new(close, span.end), FlatShape::Block));
+        for (pattern, _) in &match_block.arms {
+            if let Some(guard) = &pattern.guard {
+                flat.extend(flatten_expression(working_set, guard));
+            }
+        }
+    }
+    flat.sort_by_key(|(span, _)| span.start);
+    flat
+}
+
+/// make the braces of the blocks of the `module`s part of their shapes
+///
+/// Unlike the other blocks, the block of a `module` leaves out its braces, which are
+/// then added to its first and last shapes, or get shapes of their own.
+fn add_module_braces(
+    mut flat: Vec<(Span, FlatShape)>,
+    working_set: &StateWorkingSet,
+    block: &Block,
+) -> Vec<(Span, FlatShape)> {
+    let module_blocks: Vec<Span> = parsed_blocks(working_set, block)
+        .flat_map(|block| &block.pipelines)
+        .flat_map(|pipeline| &pipeline.elements)
+        .filter_map(|element| match &element.expression().expr {
+            Expr::Call(call) if working_set.get_decl(call.decl_id).name() == "module" => call
+                .positional_iter()
+                .find_map(|argument| match argument.expr {
+                    Expr::Block(_) => Some(argument.span),
+                    _ => None,
+                }),
+            _ => None,
+        })
+        .filter(|inner| {
+            inner.start > 0
+                && working_set
+                    .get_span_contents(Span::new(inner.start - 1, inner.end + 1))
+                    .starts_with(b"{")
+                && working_set
+                    .get_span_contents(Span::new(inner.start - 1, inner.end + 1))
+                    .ends_with(b"}")
+        })
+        .collect();
+
+    for inner in module_blocks {
+        let is_block = |shape: &FlatShape| matches!(shape, FlatShape::Block);
+        match flat
+            .iter_mut()
+            .find(|(span, shape)| span.start == inner.start && is_block(shape))
+        {
+            Some((span, _)) => span.start -= 1,
+            None => flat.push((Span::new(inner.start - 1, inner.start), FlatShape::Block)),
+        }
+        match flat.iter_mut().find(|(span, shape)| {
+            span.end == inner.end && span.start >= inner.start && is_block(shape)
+        }) {
+            Some((span, _)) => span.end += 1,
+            None => flat.push((Span::new(inner.end, inner.end + 1), FlatShape::Block)),
+        }
+    }
+    flat.sort_by_key(|(span, _)| (span.start, span.end));
+    flat
+}
+
+/// where the parts of the arms of the `match_blocks` start, with what goes before them
+///
+/// Those are the ` if ` before a guard, the ` => ` before a value and the ` | ` between
+/// the alternatives of a pattern, e.g. `1 | 2`.
+fn match_separators(match_blocks: &[MatchBlock]) -> Vec<(usize, &'static [u8])> {
+    let mut separators = vec![];
+    for (pattern, value) in match_blocks.iter().flat_map(|block| &block.arms) {
+        if let Pattern::Or(alternatives) = &pattern.pattern {
+            separators.extend(
+                alternatives
+                    .iter()
+                    .skip(1)
+                    .map(|alternative| (alternative.span.start, &b" | "[..])),
+            );
+        }
+        if let Some(guard) = &pattern.guard {
+            separators.push((guard.span.start, &b" if "[..]));
+        }
+        separators.push((value.span.start, &b" => "[..]));
+    }
+    separators
+}
+
+/// where the items of the list and record patterns of the `match_blocks` start, except
+/// the first item of each pattern
+fn pattern_items(match_blocks: &[MatchBlock]) -> Vec<usize> {
+    fn add_items(pattern: &MatchPattern, items: &mut Vec<usize>) {
+        let nested: Vec<&MatchPattern> = match &pattern.pattern {
+            Pattern::List(patterns) | Pattern::Or(patterns) => patterns.iter().collect(),
+            Pattern::Record(fields) => fields.iter().map(|(_, pattern)| pattern).collect(),
+            _ => vec![],
+        };
+        if matches!(pattern.pattern, Pattern::List(_) | Pattern::Record(_)) {
+            items.extend(nested.iter().skip(1).map(|item| item.span.start));
+        }
+        for item in nested {
+            add_items(item, items);
+        }
+    }
+
+    let mut items = vec![];
+    for (pattern, _) in match_blocks.iter().flat_map(|block| &block.arms) {
+        add_items(pattern, &mut items);
+    }
+    items
+}
+
+/// whether the `bytes` of a match pattern shape open or close a list or record pattern
+fn is_pattern_delimiter(bytes: &[u8]) -> bool {
+    let bytes = trim_ascii_whitespace(bytes);
+    matches!(bytes.first(), Some(b'[' | b'{' | b',')) || matches!(bytes.last(), Some(b']' | b'}'))
+}
+
+/// format a part of a list or record pattern found between its items, e.g. `, b: ` in
+/// `{a: $a, b: $b}`
+///
+/// The whitespace is collapsed, `,` and `:` are followed by a single space, and there is
+/// no space inside the brackets nor trailing `,` before the closing one.
+fn format_pattern_part(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut space = false;
+    let mut quote = None;
+    for &byte in trim_ascii_whitespace(bytes) {
+        match (quote, byte) {
+            (Some(open), _) => {
+                out.push(byte);
+                if byte == open {
+                    quote = None;
+                }
+            }
+            (None, byte) if byte.is_ascii_whitespace() => space = true,
+            (None, b',' | b':') => {
+                out.push(byte);
+                space = true;
+            }
+            (None, b']' | b'}') => {
+                while out.last() == Some(&b',') {
+                    out.pop();
+                }
+                out.push(byte);
+                space = false;
+            }
+            (None, _) => {
+                if space && !matches!(out.last(), None | Some(b'[' | b'{')) {
+                    out.push(b' ');
+                }
+                if matches!(byte, b'"' | b'\'' | b'`') {
+                    quote = Some(byte);
+                }
+                out.push(byte);
+                space = false;
+            }
+        }
+    }
+    if space && matches!(out.last(), Some(b',' | b':')) {
+        out.push(b' ');
+    }
+    out
+}
+
+/// where the values of the long flags of all the calls start, e.g. `foo` in `--name foo`
+fn long_flag_values(working_set: &StateWorkingSet, block: &Block) -> Vec<usize> {
+    parsed_blocks(working_set, block)
+        .flat_map(|block| &block.pipelines)
+        .flat_map(|pipeline| &pipeline.elements)
+        .filter_map(|element| match &element.expression().expr {
+            Expr::Call(call) => Some(call),
+            _ => None,
+        })
+        .flat_map(|call| &call.arguments)
+        .filter_map(|argument| match argument {
+            Argument::Named((name, _, Some(value)))
+                if working_set.get_span_contents(name.span).starts_with(b"--") =>
+            {
+                Some(value.span.start)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// the calls alone in a subexpression, e.g. `(ls --all)`, with where their arguments start
+///
+/// Those are the only calls that can have one argument per line: anywhere else, a newline
+/// ends the call. The calls of keywords, e.g. `let`, and the ones with a keyword argument,
+/// e.g. the `else` of `if`, are left out.
+fn breakable_calls(
+    contents: &[u8],
+    working_set: &StateWorkingSet,
+    block: &Block,
+) -> Vec<(Span, Vec<usize>)> {
+    let in_parentheses = |span: Span| {
+        trim_ascii_whitespace(&contents[..span.start]).ends_with(b"(")
+            && trim_ascii_whitespace(&contents[span.end..]).starts_with(b")")
+    };
+    parsed_blocks(working_set, block)
+        .flat_map(|block| &block.pipelines)
+        .filter_map(|pipeline| match pipeline.elements.as_slice() {
+            [element] => Some(element.expression()),
+            _ => None,
+        })
+        .filter(|expression| in_parentheses(expression.span))
+        .filter_map(|expression| {
+            let arguments: Vec<&Expression> = match &expression.expr {
+                Expr::Call(call) if !working_set.get_decl(call.decl_id).is_parser_keyword() => call
+                    .arguments
+                    .iter()
+                    .filter_map(|argument| match argument {
+                        Argument::Positional(expression) | Argument::Unknown(expression) => {
+                            Some(expression)
+                        }
+                        Argument::Named(_) => None,
+                    })
+                    .collect(),
+                Expr::ExternalCall(_, arguments, _) => arguments.iter().collect(),
+                _ => return None,
+            };
+            if arguments
+                .iter()
+                .any(|argument| matches!(argument.expr, Expr::Keyword(..)))
+            {
+                return None;
+            }
+            let starts: Vec<usize> = match &expression.expr {
+                Expr::Call(call) => call
+                    .arguments
+                    .iter()
+                    .map(|argument| argument.span().start)
+                    .collect(),
+                _ => arguments
+                    .iter()
+                    .map(|argument| argument.span.start)
+                    .collect(),
+            };
+            (!starts.is_empty()).then_some((expression.span, starts))
+        })
+        .collect()
+}
+
+/// the spans of all the pipelines of the top-level `block`, nested ones included
+fn all_pipelines(working_set: &StateWorkingSet, block: &Block) -> Vec<Span> {
+    parsed_blocks(working_set, block)
+        .flat_map(|block| &block.pipelines)
+        .filter_map(|pipeline| pipeline_span(working_set, pipeline))
+        .collect()
+}
+
+/// the smallest of the `pipelines` containing `span`
+fn innermost_pipeline(pipelines: &[Span], span: Span) -> Option<Span> {
+    pipelines
+        .iter()
+        .filter(|pipeline| pipeline.start <= span.start && span.end <= pipeline.end)
+        .min_by_key(|pipeline| pipeline.end - pipeline.start)
+        .copied()
+}
+
+/// whether the code at `span` fits on a line starting at `column`
+///
+/// The whitespace of the code is counted as single spaces, whatever it is, and the commas
+/// as whitespace, while the whitespace inside brackets, braces and parentheses is left
+/// out, so that the code has the same width whether its lists, records, tables or blocks
+/// are written on a single line or on several.
+fn fits_on_the_line(
+    working_set: &StateWorkingSet,
+    span: Span,
+    column: usize,
+    config: &Config,
+) -> bool {
+    let mut code: Vec<u8> = vec![];
+    for &byte in working_set.get_span_contents(span) {
+        let byte = if byte == b',' { b' ' } else { byte };
+        if byte.is_ascii_whitespace() {
+            if !matches!(code.last(), None | Some(b' ' | b'[' | b'{' | b'(')) {
+                code.push(b' ');
+            }
+        } else {
+            if matches!(byte, b']' | b'}' | b')') && code.last() == Some(&b' ') {
+                code.pop();
+            }
+            code.push(byte);
+        }
+    }
+    let width = code.trim_ascii_end().len() + 1;
+    column + width <= config.max_width
+}
+
+/// whether the block, or closure, at `span` with that many `pipelines` stays on a single line
+///
+/// This is the only place deciding it, and it does not depend on how the block is
+/// laid out in the source: the whitespace is counted as single spaces, whatever it is.
+/// A block is written on several lines when it has more than `closure_inline_max_elements`
+/// pipelines, when it would not fit on the current line of `out`, when it has comments or
+/// when it has a `match` block, whose arms are always on their own lines.
+/// With `always_expand_blocks`, only a single pipeline without nested structures stays
+/// on a single line, a subexpression being left to the other rules.
+fn should_inline_block(
+    working_set: &StateWorkingSet,
+    span: Span,
+    pipelines: usize,
+    match_blocks: &[MatchBlock],
+    out: &[u8],
+    config: &Config,
+) -> bool {
+    fits_on_the_line(working_set, span, current_column(out), config)
+        && pipelines <= config.closure_inline_max_elements
+        && !has_comment(working_set.get_span_contents(span))
+        && !match_blocks
+            .iter()
+            .any(|block| span.start < block.span.start && block.span.end <= span.end)
+        && !(config.always_expand_blocks
+            && working_set.get_span_contents(span).starts_with(b"{")
+            && (pipelines > 1 || has_nested_structure(working_set.get_span_contents(span))))
+}
+
+/// whether there is a block, list, record or subexpression inside the block `bytes`
+fn has_nested_structure(bytes: &[u8]) -> bool {
+    let inner = bytes.strip_prefix(b"{").unwrap_or(bytes);
+    let inner = inner.strip_suffix(b"}").unwrap_or(inner);
+    let (tokens, _) = lex(inner, 0, &[], &[], true);
+    tokens.iter().any(|token| {
+        token.contents == TokenContents::Item
+            && matches!(inner.get(token.span.start), Some(b'{' | b'(' | b'['))
+    })
+}
+
+/// whether there is a comment in `bytes`, or in any block, list, ... nested in them
+fn has_comment(bytes: &[u8]) -> bool {
+    let (tokens, _) = lex(bytes, 0, &[], &[], false);
+    tokens.iter().any(|token| match token.contents {
+        TokenContents::Comment => true,
+        TokenContents::Item => {
+            let item = &bytes[token.span.start..token.span.end];
+            matches!(item.first(), Some(b'{' | b'(' | b'[')) && has_comment(&item[1..])
+        }
+        _ => false,
+    })
+}
+
+/// remove the spaces at the end of `out`
+fn trim_trailing_spaces(mut out: Vec<u8>) -> Vec<u8> {
+    while out.last() == Some(&b' ') {
+        out.pop();
+    }
+    out
+}
+
+/// the number of spaces at the start of the current line of `out`
+fn current_indentation(out: &[u8]) -> usize {
+    let line_start = out.len() - current_column(out);
+    out[line_start..]
+        .iter()
+        .take_while(|&&byte| byte == b' ')
+        .count()
+}
+
+/// separate the next part of an import pattern, e.g. `use foo [bar baz]`, from the previous one
+fn space_before_import_part(mut out: Vec<u8>) -> Vec<u8> {
+    if !matches!(out.last(), None | Some(b' ') | Some(b'\n') | Some(b'[')) {
+        out.push(b' ');
+    }
+    out
+}
+
+/// the members of the import pattern list whose `[` is right before `flat`, and the end of the
+/// last one
+///
+/// Returns `None` if the list is not a plain list of names, e.g. if it has comments.
+fn import_members<'a>(
+    flat: &[(Span, FlatShape)],
+    working_set: &'a StateWorkingSet,
+) -> Option<ImportMembers<'a>> {
+    let mut members = vec![];
+    let mut separator: &[u8] = b" ";
+    let mut end = None;
+    for (span, shape) in flat {
+        let bytes = working_set.get_span_contents(*span);
+        match shape {
+            FlatShape::String => {
+                members.push(bytes);
+                end = Some(span.end);
+            }
+            FlatShape::List if trim_ascii_whitespace(bytes) == b"]" => break,
+            FlatShape::List if trim_ascii_whitespace(bytes) == b"," => separator = b", ",
+            FlatShape::List if trim_ascii_whitespace(bytes).is_empty() => {}
+            _ => return None,
+        }
+    }
+    Some(ImportMembers {
+        names: members,
+        separator,
+        end: end?,
+    })
+}
+
+/// the names listed in an import pattern, e.g. the `b a` of `use foo [b a]`
+struct ImportMembers<'a> {
+    names: Vec<&'a [u8]>,
+    /// `, ` if the names are separated by commas in the source, a space otherwise
+    separator: &'static [u8],
+    /// the end of the last name
+    end: usize,
+}
+
+impl ImportMembers<'_> {
+    /// the names sorted alphabetically, without duplicates, separated like in the source
+    fn sorted(mut self) -> Vec<u8> {
+        self.names.sort_unstable();
+        self.names.dedup();
+        self.names.join(self.separator)
+    }
+}
+
+/// write the digits of a hexadecimal integer, e.g. `0xff`, in the given `case`
+///
+/// The other integers, the `0x` prefix and the `_` separators are kept as they are written.
+fn format_int(bytes: &[u8], case: IntLiteralCase) -> Vec<u8> {
+    let Some(digits) = bytes.strip_prefix(b"0x") else {
+        return bytes.to_vec();
+    };
+
+    let digits = match case {
+        IntLiteralCase::Preserve => digits.to_vec(),
+        IntLiteralCase::Upper => digits.to_ascii_uppercase(),
+        IntLiteralCase::Lower => digits.to_ascii_lowercase(),
+    };
+    [&b"0x"[..], &digits[..]].concat()
+}
+
+/// separate the groups of digits of a binary literal, e.g. `0x[AA  BB]`, with single spaces
+///
+/// The digits themselves are kept as they are written.
+fn format_binary(bytes: &[u8]) -> Vec<u8> {
+    let Some(open) = bytes.iter().position(|&byte| byte == b'[') else {
+        return bytes.to_vec();
+    };
+    let Some(inner) = bytes[open + 1..].strip_suffix(b"]") else {
+        return bytes.to_vec();
+    };
+
+    let groups: Vec<&[u8]> = inner
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|group| !group.is_empty())
+        .collect();
+    [&bytes[..=open], &groups.join(&b' ')[..], b"]"].concat()
+}
+
+/// add a space just inside a lone `brace` that has just been written to `out`
+///
+/// Empty braces, `{}`, are never lone braces so they are left as they are.
+fn space_inside_brace(brace: &[u8], mut out: Vec<u8>) -> Vec<u8> {
+    match brace {
+        b"{" => out.push(b' '),
+        b"}" if !matches!(out.iter().rev().nth(1), Some(b' ') | Some(b'\n')) => {
+            out.insert(out.len() - 1, b' ');
+        }
+        _ => {}
+    }
+    out
+}
+
+/// make sure there is a `line_ending` at the end of a buffer
+///
+/// An empty buffer is left empty, so that an empty file stays that way.
+pub(crate) fn add_newline_at_end_of_file(mut out: Vec<u8>, line_ending: &[u8]) -> Vec<u8> {
+    if !out.is_empty() && out.last() != Some(&b'\n') {
+        out.extend(line_ending);
+    }
+    out
+}
+
+/// strip all spaces, new lines and tabs found a sequence of bytes
+///
+/// Because you don't know how the incoming code is formatted,
+/// the best way to format is to strip all the whitespace
+/// and afterwards include the new lines and indentation correctly
+/// according to the configuration
+fn trim_ascii_whitespace(x: &[u8]) -> &[u8] {
+    let Some(from) = x.iter().position(|x| !x.is_ascii_whitespace()) else {
+        return &x[0..0];
+    };
+    let to = x.iter().rposition(|x| !x.is_ascii_whitespace()).unwrap();
+    let result = &x[from..=to];
+    let printable = String::from_utf8_lossy(result).to_string();
+    trace!("stripped the whitespace, result: {:?}", printable);
+    result
+}
+
+/// return true if the Nushell block has at least 1 pipeline with some code
+///
+/// This function exists because sometimes is passed to `nufmt` an empty String,
+/// or a nu code which the parser can't identify something runnable
 /// (like a list of comments)
 ///
 /// We don't want to return a blank file if that is the case,
 /// so this check gives the opportunity to `nufmt`
 /// to know when not to touch the file at all in the implementation.
-fn block_has_pipelines(block: &Block) -> bool {
-    !block.pipelines.is_empty()
+/// The parser can also make an empty pipeline of code it can't tokenize, e.g. a lone `}`.
+fn block_has_code(working_set: &StateWorkingSet, block: &Block) -> bool {
+    flatten_block(working_set, block)
+        .iter()
+        .any(|(span, _)| span.end > span.start)
+}
+
+/// return the span of the first piece of code the parser could not make sense of, if any
+///
+/// Formatting such code is not safe, because the meaning of the script could be changed.
+fn find_garbage(flat: &[(Span, FlatShape)]) -> Option<Span> {
+    flat.iter()
+        .find(|(_, shape)| matches!(shape, FlatShape::Garbage))
+        .map(|(span, _)| *span)
+}
+
+/// return the span of a top-level pipeline, from its first to its last token
+fn pipeline_span(working_set: &StateWorkingSet, pipeline: &Pipeline) -> Option<Span> {
+    let flat = flatten_pipeline(working_set, pipeline);
+    let first = flat.first()?.0;
+    let last = flat.last()?.0;
+    Some(Span::new(first.start, last.end))
+}
+
+/// return the spans of the top-level pipelines that contain some garbage
+fn find_garbage_pipelines(working_set: &StateWorkingSet, block: &Block) -> Vec<Span> {
+    block
+        .pipelines
+        .iter()
+        .filter(|pipeline| find_garbage(&flatten_pipeline(working_set, pipeline)).is_some())
+        .filter_map(|pipeline| pipeline_span(working_set, pipeline))
+        .collect()
+}
+
+/// return the spans of the top-level pipelines right below a `# nufmt: skip` comment
+fn find_skipped_pipelines(
+    contents: &[u8],
+    working_set: &StateWorkingSet,
+    block: &Block,
+) -> Vec<Span> {
+    block
+        .pipelines
+        .iter()
+        .filter_map(|pipeline| pipeline_span(working_set, pipeline))
+        .filter(|span| is_preceded_by_directive(contents, span.start, SKIP_DIRECTIVE))
+        .collect()
+}
+
+/// return the spans covering the top-level pipelines of each region where formatting is off
+///
+/// All the pipelines of a region are merged into a single span, so that everything
+/// between them, e.g. blank lines and comments, is kept verbatim as well.
+fn find_disabled_pipelines(
+    contents: &[u8],
+    working_set: &StateWorkingSet,
+    block: &Block,
+) -> Vec<Span> {
+    let pipelines: Vec<Span> = block
+        .pipelines
+        .iter()
+        .filter_map(|pipeline| pipeline_span(working_set, pipeline))
+        .collect();
+
+    find_off_regions(contents)
+        .into_iter()
+        .filter_map(|region| {
+            let mut inside = pipelines
+                .iter()
+                .filter(|span| region.start <= span.start && span.end <= region.end);
+            let first = inside.next()?;
+            let last = inside.next_back().unwrap_or(first);
+            Some(Span::new(first.start, last.end))
+        })
+        .collect()
+}
+
+/// return the byte ranges between each `# nufmt: off` comment and the next `# nufmt: on` one
+///
+/// A region that is never turned back on lasts until the end of the contents.
+fn find_off_regions(contents: &[u8]) -> Vec<Span> {
+    let mut regions = vec![];
+    let mut region_start = None;
+    let mut line_start = 0;
+
+    for line in contents.split(|&byte| byte == b'\n') {
+        let line_end = line_start + line.len();
+        let comment = trim_ascii_whitespace(line);
+
+        match region_start {
+            None if comment == OFF_DIRECTIVE.as_bytes() => region_start = Some(line_end),
+            Some(start) if comment == ON_DIRECTIVE.as_bytes() => {
+                regions.push(Span::new(start, line_start));
+                region_start = None;
+            }
+            _ => {}
+        }
+
+        line_start = line_end + 1;
+    }
+
+    if let Some(start) = region_start {
+        regions.push(Span::new(start, contents.len()));
+    }
+
+    regions
+}
+
+/// return true if the line just above the one starting at `start` is the `directive` comment
+///
+/// Only indentation is allowed between the beginning of the line and `start`.
+fn is_preceded_by_directive(contents: &[u8], start: usize, directive: &str) -> bool {
+    let mut lines = contents[..start].split(|&byte| byte == b'\n').rev();
+
+    match lines.next() {
+        Some(indentation) if trim_ascii_whitespace(indentation).is_empty() => {}
+        _ => return false,
+    }
+
+    matches!(lines.next(), Some(line) if trim_ascii_whitespace(line) == directive.as_bytes())
+}
+
+/// return the pipeline, among `pipelines`, containing the `span`
+fn find_pipeline(span: Span, pipelines: &[Span]) -> Option<&Span> {
+    pipelines
+        .iter()
+        .find(|pipeline| pipeline.start <= span.start && span.end <= pipeline.end)
+}
+
+/// remove all the shapes inside the `pipelines`
+fn without_pipelines(flat: &[(Span, FlatShape)], pipelines: &[Span]) -> Vec<(Span, FlatShape)> {
+    flat.iter()
+        .filter(|(span, _)| find_pipeline(*span, pipelines).is_none())
+        .cloned()
+        .collect()
+}
+
+/// replace all the shapes inside each of the `verbatim_pipelines` by a single garbage shape
+///
+/// This way, the formatter can echo those pipelines verbatim while still
+/// formatting everything around them.
+fn merge_verbatim_pipelines(
+    flat: Vec<(Span, FlatShape)>,
+    verbatim_pipelines: &[Span],
+) -> Vec<(Span, FlatShape)> {
+    let mut merged = vec![];
+    for (span, shape) in flat {
+        match find_pipeline(span, verbatim_pipelines) {
+            Some(pipeline) if pipeline.start == span.start => {
+                merged.push((*pipeline, FlatShape::Garbage));
+            }
+            Some(_) => {}
+            None => merged.push((span, shape)),
+        }
+    }
+    merged
 }
 
 /// return true if the given span is the last one
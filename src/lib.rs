@@ -2,39 +2,309 @@
 //!
 //! It does not do anything more than that, which makes it so fast.
 use config::Config;
-use formatting::{add_newline_at_end_of_file, format_inner};
+use formatting::{
+    add_newline_at_end_of_file, format_code, format_inner, format_range_inner, line_ending_of,
+};
 use log::{debug, trace};
+use nu_protocol::Span;
+use std::fmt;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 pub mod config;
+#[cfg(feature = "paths")]
+mod files;
 mod formatting;
+mod markdown;
+
+pub use markdown::format_markdown;
+
+#[cfg(feature = "paths")]
+pub use files::{
+    excluded_files, format_paths, format_paths_in, is_excluded, FileDiagnostic, Mode, SearchOptions,
+};
+
+/// the version of the formatter, the same code can be formatted differently by another one
+pub const FORMATTER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// the ways formatting some Nushell code can fail
+#[derive(Debug)]
+pub enum FormatError {
+    /// the code could not be read from its source or written to its destination
+    Io(std::io::Error),
+    /// the parser found some invalid code, located at the given byte range of the input
+    GarbageFound {
+        span_start: usize,
+        span_end: usize,
+        snippet: String,
+    },
+    /// one of the globs of [`config::Config::includes`] or [`config::Config::excludes`]
+    /// is invalid
+    InvalidPattern(String),
+    /// the formatted code is not valid UTF-8 from the given byte offset, which is a bug of
+    /// the formatter
+    InvalidUtf8 { offset: usize },
+}
+
+impl FormatError {
+    /// move the location of the error by `offset` bytes
+    ///
+    /// This is needed when only a part of the input, e.g. without its shebang,
+    /// was given to the parser.
+    pub(crate) fn shifted_by(self, offset: usize) -> Self {
+        match self {
+            FormatError::GarbageFound {
+                span_start,
+                span_end,
+                snippet,
+            } => FormatError::GarbageFound {
+                span_start: span_start + offset,
+                span_end: span_end + offset,
+                snippet,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(err) => write!(f, "could not read or write the code: {err}"),
+            FormatError::GarbageFound {
+                span_start,
+                span_end,
+                snippet,
+            } => write!(
+                f,
+                "found invalid code at bytes {span_start}..{span_end}: {snippet}"
+            ),
+            FormatError::InvalidPattern(err) => write!(f, "invalid pattern of files: {err}"),
+            FormatError::InvalidUtf8 { offset } => write!(
+                f,
+                "the formatted code is not valid UTF-8 from byte {offset}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// a remark about the formatting of some code that did not prevent it, e.g. a part of
+/// the code left as it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// the start of the byte range of the input the remark is about
+    pub span_start: usize,
+    /// the end, excluded, of that byte range
+    pub span_end: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(span: Span, message: &str) -> Self {
+        Diagnostic {
+            span_start: span.start,
+            span_end: span.end,
+            message: message.to_string(),
+        }
+    }
+
+    /// move the location of the remark by `offset` bytes, see [`FormatError::shifted_by`]
+    pub(crate) fn shifted_by(self, offset: usize) -> Self {
+        Diagnostic {
+            span_start: self.span_start + offset,
+            span_end: self.span_end + offset,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bytes {}..{}: {}",
+            self.span_start, self.span_end, self.message
+        )
+    }
+}
+
+impl From<std::io::Error> for FormatError {
+    fn from(err: std::io::Error) -> Self {
+        FormatError::Io(err)
+    }
+}
+
+/// a hash of `config` and of the version of the formatter, e.g. to key a cache of formatted files
+///
+/// Two calls give the same hash for the same configuration and version, from one run to the
+/// other, so a file formatted with the same signature does not need to be formatted again.
+pub fn format_signature(config: &Config) -> u64 {
+    let mut hasher = StableHasher::default();
+    FORMATTER_VERSION.hash(&mut hasher);
+    config.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// a 64-bit FNV-1a hasher, unlike the hasher of the standard library its output is the same
+/// with every version of Rust
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
 
 /// format a Nushell file inplace
-pub fn format_single_file(file: &PathBuf, config: &Config) {
-    let contents = std::fs::read(file)
-        .unwrap_or_else(|_| panic!("something went wrong reading the file {}", file.display()));
+///
+/// Returns whether the file was changed.
+/// The file is left untouched if it contains invalid code, or if it can't be read or written.
+pub fn format_single_file(file: &PathBuf, config: &Config) -> Result<bool, FormatError> {
+    let contents = std::fs::read(file)?;
 
-    let formatted_bytes = add_newline_at_end_of_file(format_inner(&contents, config));
+    let formatted_bytes = format_file_contents(&contents, config)?;
 
     if formatted_bytes == contents {
         debug!("File is already formatted correctly.");
+        return Ok(false);
     }
 
-    let mut writer = File::create(file).unwrap();
-    let file_bytes = formatted_bytes.as_slice();
-    writer
-        .write_all(file_bytes)
-        .expect("something went wrong writing");
+    write_file(file, &formatted_bytes)?;
     trace!("written");
+    Ok(true)
+}
+
+/// replace the contents of the file at `path`, keeping its permissions
+///
+/// The contents are written to a temporary file next to it, which is then renamed over it,
+/// so an interrupted run leaves either the old file or the new one, never half of it.
+/// A symbolic link is left as it is, the file it points to is replaced.
+pub(crate) fn write_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let path = std::fs::canonicalize(path)?;
+    let permissions = std::fs::metadata(&path)?.permissions();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".nufmt-{}.tmp", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+
+    let written = File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::set_permissions(&temporary, permissions))
+        .and_then(|()| std::fs::rename(&temporary, &path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// format the contents of a Nushell file, as they would be written back to disk
+pub fn format_file_contents(contents: &[u8], config: &Config) -> Result<Vec<u8>, FormatError> {
+    let line_ending = line_ending_of(contents, config);
+    format_inner(contents, config, &mut vec![])
+        .map(|out| add_newline_at_end_of_file(out, line_ending))
+}
+
+/// tell whether the contents of a Nushell file are already formatted
+///
+/// This is the same decision [`format_single_file`] makes to leave a file untouched,
+/// the line endings of `input` being kept by the formatting, but nothing is written
+/// and the formatted bytes are dropped as soon as they are compared.
+pub fn is_formatted(input: &[u8], config: &Config) -> Result<bool, FormatError> {
+    Ok(format_file_contents(input, config)? == input)
+}
+
+/// format some Nushell code and write the result into `out`
+///
+/// The output is the same as [`format_file_contents`], but it is streamed to the
+/// writer instead of being returned.
+pub fn format_to_writer<W: Write>(
+    input: &[u8],
+    config: &Config,
+    out: &mut W,
+) -> Result<(), FormatError> {
+    out.write_all(&format_file_contents(input, config)?)?;
+    Ok(())
+}
+
+/// format all the Nushell code that can be read from `input`
+///
+/// The output is the same as [`format_file_contents`], the input is read as raw
+/// bytes so it does not need to be valid UTF-8 line by line.
+pub fn format_reader<R: Read>(input: &mut R, config: &Config) -> Result<Vec<u8>, FormatError> {
+    let mut contents = Vec::new();
+    input.read_to_end(&mut contents)?;
+    format_file_contents(&contents, config)
+}
+
+/// format only the top-level pipelines of `input` found between the 1-based lines
+/// `start` and `end`, both included
+///
+/// The pipelines only partly in the range, and everything around them, are left
+/// byte for byte as they are.
+pub fn format_range(
+    input: &[u8],
+    config: &Config,
+    start: usize,
+    end: usize,
+) -> Result<Vec<u8>, FormatError> {
+    format_range_inner(input, config, start, end)
 }
 
 /// format a string of Nushell code
-pub fn format_string(input_string: &String, config: &Config) -> String {
-    let contents = input_string.as_bytes();
-    let formatted_bytes = format_inner(contents, config);
-    String::from_utf8(formatted_bytes).unwrap()
+pub fn format_string(input_string: &str, config: &Config) -> Result<String, FormatError> {
+    format_string_with_diagnostics(input_string, config).0
+}
+
+/// format a single expression or pipeline, e.g. a snippet of configuration or an entry of
+/// the history of the REPL
+///
+/// Unlike [`format_string`], the input is not a file: it has no shebang nor byte order mark,
+/// and the whitespace around the code, including the end of the last line, is dropped.
+pub fn format_expression_str(input: &str, config: &Config) -> Result<String, FormatError> {
+    let offset = input.len() - input.trim_start().len();
+    let formatted = format_code(input.trim().as_bytes(), config, &mut vec![])
+        .map_err(|err| err.shifted_by(offset))?;
+    into_string(formatted).map(|formatted| formatted.trim_end().to_string())
+}
+
+/// format a string of Nushell code, see [`format_string`], and tell what did not go as usual
+///
+/// The diagnostics are not errors, e.g. they point to the parts of the code left as they are.
+pub fn format_string_with_diagnostics(
+    input_string: &str,
+    config: &Config,
+) -> (Result<String, FormatError>, Vec<Diagnostic>) {
+    let mut diagnostics = vec![];
+    let formatted =
+        format_inner(input_string.as_bytes(), config, &mut diagnostics).and_then(into_string);
+    (formatted, diagnostics)
+}
+
+/// turn formatted bytes back into a string, they are only invalid UTF-8 if the formatter
+/// cut a character
+fn into_string(formatted_bytes: Vec<u8>) -> Result<String, FormatError> {
+    String::from_utf8(formatted_bytes).map_err(|err| FormatError::InvalidUtf8 {
+        offset: err.utf8_error().valid_up_to(),
+    })
 }
 
 #[cfg(test)]
@@ -45,10 +315,1335 @@ mod test {
     /// 1. formatting the input gives the expected result
     /// 2. formatting the output of `nufmt` a second time does not change the content
     fn run_test(input: &str, expected: &str) {
-        let formatted = format_string(&input.to_string(), &Config::default());
+        run_test_with_config(input, expected, &Config::default());
+    }
+
+    /// same as [`run_test`] with a custom configuration
+    fn run_test_with_config(input: &str, expected: &str, config: &Config) {
+        let formatted = format_string(input, config).unwrap();
 
         assert_eq!(expected.to_string(), formatted);
-        assert_eq!(formatted, format_string(&formatted, &Config::default()));
+        assert_eq!(formatted, format_string(&formatted, config).unwrap());
+    }
+
+    #[test]
+    fn format_into_a_writer() {
+        let mut out: Vec<u8> = vec![];
+        format_to_writer(b"   0", &Config::default(), &mut out).unwrap();
+
+        assert_eq!(out, b"0\n");
+    }
+
+    #[test]
+    fn format_from_a_reader() {
+        let mut input = std::io::Cursor::new(b"# no newline after the code\nlet x  =  1".to_vec());
+        let output = format_reader(&mut input, &Config::default()).unwrap();
+        assert_eq!(output, b"# no newline after the code\nlet x = 1\n");
+    }
+
+    #[test]
+    fn keep_crlf_line_endings() {
+        let input = "# comment\r\nlet x  =  1\r\n";
+        let expected = "# comment\r\nlet x = 1";
+        run_test(input, expected);
+
+        let output = format_file_contents(input.as_bytes(), &Config::default()).unwrap();
+        assert_eq!(output, b"# comment\r\nlet x = 1\r\n");
+    }
+
+    #[test]
+    fn force_line_endings() {
+        let config = Config {
+            line_ending: config::LineEnding::Lf,
+            ..Config::default()
+        };
+        let output = format_file_contents(b"# comment\r\nlet x = 1\r\n", &config).unwrap();
+        assert_eq!(output, b"# comment\nlet x = 1\n");
+
+        let config = Config {
+            line_ending: config::LineEnding::Crlf,
+            ..Config::default()
+        };
+        let output = format_file_contents(b"# comment\nlet x = 1", &config).unwrap();
+        assert_eq!(output, b"# comment\r\nlet x = 1\r\n");
+    }
+
+    #[test]
+    fn keep_byte_order_mark() {
+        let input = "\u{feff}let x  =  1";
+        let expected = "\u{feff}let x = 1";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn strip_byte_order_mark() {
+        let config = Config {
+            strip_bom: true,
+            ..Config::default()
+        };
+        let output = format_file_contents("\u{feff}let x  =  1".as_bytes(), &config).unwrap();
+        assert_eq!(output, b"let x = 1\n");
+    }
+
+    #[test]
+    fn format_only_a_range_of_lines() {
+        let input = "let a  =  1\ndef foo [  x  ] { 1 }\ndef bar [ ] { 2 }\nlet b  =  2\n";
+        let expected = "let a  =  1\ndef foo [x] {1}\ndef bar [] {2}\nlet b  =  2\n";
+        let output = format_range(input.as_bytes(), &Config::default(), 2, 3).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn keep_pipelines_partly_in_the_range() {
+        let input = "let a  =  1\ndef foo [] {\n    1  +  1\n}\n";
+        let output = format_range(input.as_bytes(), &Config::default(), 1, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            input.replace("a  =  1", "a = 1")
+        );
+    }
+
+    #[test]
+    fn garbage_is_reported_with_its_location() {
+        let input = "#!/usr/bin/env nu\nls | each 10kb";
+        let err = format_string(input, &Config::default()).unwrap_err();
+
+        match err {
+            FormatError::GarbageFound {
+                span_start,
+                span_end,
+                snippet,
+            } => {
+                assert_eq!(&input[span_start..span_end], snippet);
+                assert!(snippet.contains("10kb"));
+            }
+            err => panic!("expected garbage to be found, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn code_that_can_not_be_tokenized_is_an_error() {
+        let err = format_string("}\n", &Config::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            FormatError::GarbageFound { span_start: 0, .. }
+        ));
+
+        let config = Config {
+            skip_garbage: true,
+            ..Config::default()
+        };
+        let (formatted, diagnostics) = format_string_with_diagnostics("}\n", &config);
+        assert_eq!(formatted.unwrap(), "}\n");
+        assert_eq!(diagnostics.len(), 1);
+
+        // a file with only comments has no code, but nothing invalid either
+        run_test("# a comment\n", "# a comment");
+    }
+
+    #[test]
+    fn skip_garbage_pipelines() {
+        let config = Config {
+            skip_garbage: true,
+            ..Config::default()
+        };
+        let input = "let one = 1\nls | each 10kb\n{a: 1,  b: 2}";
+        let expected = "let one = 1\nls | each 10kb\n{a: 1, b: 2}";
+
+        let formatted = format_string(input, &config).unwrap();
+        assert_eq!(expected, formatted);
+        assert_eq!(formatted, format_string(&formatted, &config).unwrap());
+    }
+
+    #[test]
+    fn skip_directive_leaves_pipeline_untouched() {
+        let input = "let one = 1
+# nufmt: skip
+{a:   1,
+ b:   22}
+[1, 2]";
+        let expected = "let one = 1
+# nufmt: skip
+{a:   1,
+ b:   22}
+[1,2]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn off_region_leaves_code_untouched() {
+        let input = "let one = 1
+# nufmt: off
+let table = [
+    [name  size];
+    [foo   1kb ]
+]
+
+{a:   1}
+# nufmt: on
+[1, 2]";
+        let expected = "let one = 1
+# nufmt: off
+let table = [
+    [name  size];
+    [foo   1kb ]
+]
+
+{a:   1}
+# nufmt: on
+[1,2]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn unterminated_off_region_lasts_until_end_of_file() {
+        let input = "[1, 2]\n# nufmt: off\n[1,   2]\n\n[3,  4]";
+        let expected = "[1,2]\n# nufmt: off\n[1,   2]\n\n[3,  4]\n";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn normalize_signature_spacing() {
+        let input =
+            "def foo [x:int,y :string = \"a\", --flag(-f):int, opt?:list<int>, ...rest] { 1 }";
+        let expected =
+            "def foo [x: int, y: string = \"a\", --flag(-f): int, opt?: list<int>, ...rest] {\n    1\n}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn normalize_multiline_signature_spacing() {
+        let input = "def foo [
+  x   # untyped
+      # standalone comment
+  y :int=1
+  --verbose(-v)   # flag
+] { 1 }";
+        let expected = "def foo [
+    x # untyped
+    # standalone comment
+    y: int = 1
+    --verbose(-v) # flag
+] {1}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn break_long_signature() {
+        let input = "def foo [alpha: int, beta: string, gamma: record, delta: list<int>, eps = [1, 2, 3]] { 1 }";
+        let expected = "def foo [
+    alpha: int
+    beta: string
+    gamma: record
+    delta: list<int>
+    eps = [1, 2, 3]
+] {1}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn align_comment_blocks() {
+        let input = "[1, 2]
+    # first line
+  #   second line
+
+        # other block
+[3, 4]";
+        let expected = "[1,2]
+# first line
+#   second line
+
+# other block
+[3,4]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_comments_after_list_items() {
+        let input = "[
+  1  # first
+  2
+  3 # third
+]";
+        let expected = "[
+    1 # first
+    2
+    3 # third
+]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_items_separated_by_whitespace_apart() {
+        let input = "[1 2\n3]";
+        let expected = "[1 2 3]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_comments_after_record_items() {
+        let input = "{
+  a: 1,  # first
+  b: 2
+}";
+        let expected = "{
+    a: 1 # first
+    b: 2
+}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn indent_the_nested_collections_with_comments() {
+        let input = "def f [] {
+  let x = {a: 1, # c
+  b: [1 # one
+  2] c: [2 3]}
+  $x
+}";
+        let expected = "def f [] {
+    let x = {
+        a: 1 # c
+        b: [
+            1 # one
+            2
+        ]
+        c: [2 3]
+    }
+    $x
+}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_blank_line_between_comment_and_code() {
+        let input = "# section header\n\n\ndef foo [] { 1 }\n# doc comment\ndef bar [] { 2 }";
+        let expected = "# section header\n\ndef foo [] {1}\n# doc comment\ndef bar [] {2}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn space_external_call_arguments() {
+        let input = "git commit  -m   \"msg\" --author=me 'my file.txt' ...$rest|lines";
+        let expected = "git commit -m \"msg\" --author=me 'my file.txt' ...$rest | lines";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_the_caret_of_explicit_externals() {
+        let input = "^git   log --oneline|lines";
+        let expected = "^git log --oneline | lines";
+        run_test(input, expected);
+        run_test("ls | ^grep foo", "ls | ^grep foo");
+    }
+
+    #[test]
+    fn keep_attributes_right_above_their_command() {
+        let input = "@example \"double\" { f 1 }\n@search-terms  \"twice\"\n\ndef f [x] { 1 }";
+        let expected = "@example \"double\" { f 1 }\n@search-terms \"twice\"\ndef f [x] {1}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn separate_the_arguments_of_a_command() {
+        let cases = [
+            // after a `)`, and the cell path of a subexpression
+            ("echo (1)   2", "echo (1) 2"),
+            ("echo ({a: 1}).a  2", "echo ({a: 1}).a 2"),
+            // after a `]`
+            ("echo [1]  [2].0  3", "echo [1] [2].0 3"),
+            // after a `}`
+            ("do {|x| [$x]}   3", "do {|x| [$x]} 3"),
+            // after a word
+            ("echo a   b", "echo a b"),
+            // after a string
+            ("echo \"a\"  1 'b'", "echo \"a\" 1 'b'"),
+            // never after an opening delimiter
+            ("echo ( 1 ) [ 2 ]", "echo (1) [2]"),
+            // after an optional member of a cell path, at the end of a line or not
+            (
+                "let r = {a: 1}\necho $r.a?   2\necho $r.a?",
+                "let r = {a: 1}\necho $r.a? 2\necho $r.a?",
+            ),
+            (
+                "let r = {a: {b: 1}}\necho $r.a?.b  2",
+                "let r = {a: {b: 1}}\necho $r.a?.b 2",
+            ),
+        ];
+
+        let config = Config::default();
+        for (input, expected) in cases {
+            run_test(input, expected);
+            assert_eq!(format_string(expected, &config).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn diagnose_the_code_left_as_it_is() {
+        let config = Config {
+            skip_garbage: true,
+            ..Config::default()
+        };
+        let input = "#!/usr/bin/env nu\n# nufmt: skip\nlet x =   1\nlet = 1\n[1   2]";
+        let (formatted, diagnostics) = format_string_with_diagnostics(input, &config);
+
+        assert!(formatted.is_ok());
+        let messages: Vec<(&str, &str)> = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                (
+                    &input[diagnostic.span_start..diagnostic.span_end],
+                    diagnostic.message.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    "let x =   1",
+                    "this pipeline is left as it is because of `# nufmt: skip`"
+                ),
+                (
+                    "let = 1",
+                    "this pipeline is left as it is because it has invalid code"
+                ),
+            ]
+        );
+
+        let (_, diagnostics) = format_string_with_diagnostics("[1   2]", &Config::default());
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn leave_empty_files_empty() {
+        for input in ["", "\n\n", "   \t  "] {
+            let formatted = format_file_contents(input.as_bytes(), &Config::default()).unwrap();
+            assert_eq!(formatted, b"", "formatting {input:?}");
+            assert_eq!(
+                format_file_contents(&formatted, &Config::default()).unwrap(),
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn trim_trailing_whitespace_of_comments() {
+        run_test("# note   \nlet x = 1", "# note\nlet x = 1");
+        run_test("let x = 1  # trailing   ", "let x = 1 # trailing");
+        run_test(
+            "def f [\n  a # param   \n] {1}",
+            "def f [\n    a # param\n] {1}",
+        );
+        run_test("let x = 1\n# last   ", "let x = 1\n# last");
+    }
+
+    #[test]
+    fn format_do_blocks() {
+        run_test("do  {}", "do {}");
+        run_test("do {  }  # nothing", "do {} # nothing");
+        run_test("let x = 1\ndo  { $x }", "let x = 1\ndo {$x}");
+        run_test("do  -i  {  risky  }", "do -i {risky}");
+        run_test(
+            "do --ignore-errors {} | lines",
+            "do --ignore-errors {} | lines",
+        );
+
+        let config = Config {
+            space_inside_braces: true,
+            ..Config::default()
+        };
+        run_test_with_config("do {risky}", "do { risky }", &config);
+    }
+
+    #[test]
+    fn format_export_variants() {
+        run_test("export   def  f  [ x ]  { $x }", "export def f [x] {$x}");
+        run_test("export-env  { $env.A = 1 }", "export-env {$env.A = 1}");
+        run_test("export alias  ll  =  ls -l", "export alias ll = ls -l");
+        run_test("export   const  X  =  1", "export const X = 1");
+        run_test("export use  std  *", "export use std *");
+        run_test("export extern  git  [ ]", "export extern git []");
+        run_test(
+            "let x = 1\nexport const Y = 2\nexport def f [] {}",
+            "let x = 1\nexport const Y = 2\nexport def f [] {}",
+        );
+    }
+
+    #[test]
+    fn tell_whether_the_code_is_formatted() {
+        let config = Config::default();
+        assert!(is_formatted(b"let x = 1\n", &config).unwrap());
+        assert!(is_formatted(b"let x = 1\r\nlet y = 2\r\n", &config).unwrap());
+        assert!(!is_formatted(b"let  x = 1\n", &config).unwrap());
+        assert!(!is_formatted(b"let x = 1", &config).unwrap());
+        assert!(is_formatted(b"let = 1", &config).is_err());
+    }
+
+    #[test]
+    fn keep_comments_inside_blocks() {
+        run_test(
+            "if true { # checking\n  1\n}",
+            "if true { # checking\n    1\n}",
+        );
+        run_test(
+            "def foo [] {\n  # doc\n  1\n}",
+            "def foo [] {\n    # doc\n    1\n}",
+        );
+        run_test(
+            "let f = {|x| # identity\n  $x\n}",
+            "let f = {|x| # identity\n    $x\n}",
+        );
+        run_test(
+            "do {\n  ls   # all\n  # some\n\n\n  # more\n  ls\n  # end\n}",
+            "do {\n    ls # all\n    # some\n\n    # more\n    ls\n    # end\n}",
+        );
+        run_test(
+            "def f [] { do { # inner\n ls } }",
+            "def f [] {\n    do { # inner\n        ls\n    }\n}",
+        );
+    }
+
+    #[test]
+    fn always_expand_blocks() {
+        let input = "def f [] { ls; pwd }\ndef g [] { ls }\ndef h [] { [1 2] }";
+        run_test(
+            input,
+            "def f [] {\n    ls\n    pwd\n}\ndef g [] {ls}\ndef h [] {[1 2]}",
+        );
+
+        let config = Config {
+            always_expand_blocks: true,
+            ..Config::default()
+        };
+        let expected = "def f [] {\n    ls\n    pwd\n}\ndef g [] {ls}\ndef h [] {\n    [1 2]\n}";
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn attach_the_values_of_flags() {
+        let input = "def f [--name: string, -n: int] {}\nf --name=foo -n 3\nf --name  foo\ndo --ignore-errors {ls}";
+        let cases = [
+            (
+                config::FlagValueStyle::Space,
+                "def f [--name: string, -n: int] {}\nf --name foo -n 3\nf --name foo\ndo --ignore-errors {ls}",
+            ),
+            (
+                config::FlagValueStyle::Equals,
+                "def f [--name: string, -n: int] {}\nf --name=foo -n 3\nf --name=foo\ndo --ignore-errors {ls}",
+            ),
+            (
+                config::FlagValueStyle::Preserve,
+                "def f [--name: string, -n: int] {}\nf --name=foo -n 3\nf --name foo\ndo --ignore-errors {ls}",
+            ),
+        ];
+        for (flag_value_style, expected) in cases {
+            let config = Config {
+                flag_value_style,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn format_match_guards() {
+        let input =
+            "let x = 1\nmatch $x {  $y if $y   >    0 => { \"positive\" }, 1|2 => 2\n  _ =>   3 }";
+        let expected = "let x = 1\nmatch $x {\n    $y if $y > 0 => {\"positive\"}\n    1 | 2 => 2\n    _ => 3\n}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_list_and_record_patterns() {
+        let input = "let x = [1 2 3]\nmatch $x {\n  [$first,   ..$rest] => 1\n  [1 ,2, ..] => 2\n  [[1, 2], $e,] => 3\n  { a : $a,b:  $b } => 4\n  {$c,  $d} => 5\n  [ ] => 6\n}";
+        let expected = "let x = [1 2 3]\nmatch $x {\n    [$first, ..$rest] => 1\n    [1, 2, ..] => 2\n    [[1, 2], $e] => 3\n    {a: $a, b: $b} => 4\n    {$c, $d} => 5\n    [] => 6\n}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_keywords_with_and_without_values() {
+        let input = "def f [] {\n  return\n}\ndef g [x] { return   $x }\nfor i in [1 2] {\n  if $i == 1 { continue }\n  break\n}\nif true { 1 } else { 2 }";
+        let expected = "def f [] {return}\ndef g [x] {return $x}\nfor i in [1 2] {\n    if $i == 1 {continue}\n    break\n}\nif true {1} else {2}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn align_match_arms() {
+        let config = Config {
+            align_match_arms: true,
+            ..Config::default()
+        };
+        let input = "let x = 1\nmatch $x {\n  1 => \"one\"\n  10 | 20 => \"tens\"\n  $y if $y > 100 => \"big\"\n  _ => \"other\"\n}";
+        let expected = "let x = 1\nmatch $x {\n    1              => \"one\"\n    10 | 20        => \"tens\"\n    $y if $y > 100 => \"big\"\n    _              => \"other\"\n}";
+        run_test_with_config(input, expected, &config);
+
+        let config = Config {
+            align_match_arms: true,
+            max_width: 24,
+            ..Config::default()
+        };
+        let input = "let x = 1\nmatch $x {\n  1 => 2\n  $y if $y > 1000000 => 3\n}";
+        let expected = "let x = 1\nmatch $x {\n    1 => 2\n    $y if $y > 1000000 => 3\n}";
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn align_record_values() {
+        let config = Config {
+            align_record_values: true,
+            ..Config::default()
+        };
+        let input = "let x = {q: 1}\nlet r = {\n  a: 1 # c\n  long_key: {\n  z: 1 # d\n  yyy: 2}\n  ...$x\n  long_key: 2, b: 3\n}";
+        let expected = "let x = {q: 1}\nlet r = {\n    a:        1 # c\n    long_key: {\n        z:   1 # d\n        yyy: 2\n    }\n    ...$x\n    long_key: 2\n    b:        3\n}";
+        run_test_with_config(input, expected, &config);
+
+        let config = Config {
+            align_record_values: true,
+            max_width: 24,
+            ..Config::default()
+        };
+        let input = "let r = {a: 1, long_key_of_the_record: 2}";
+        let expected = "let r = {\n    a: 1\n    long_key_of_the_record: 2\n}";
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn format_subexpressions() {
+        let input = "let a = ( ls -la | grep foo )\nlet b = (ls --all --long --human-readable | grep something-very-long | head -n 10)\nlet c = ((1 + 2) * 3)";
+        let expected = "let a = (ls -la | grep foo)\nlet b = (\n    ls --all --long --human-readable | grep something-very-long | head -n 10\n)\nlet c = ((1 + 2) * 3)";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_doc_comments_attached_to_their_definition() {
+        let input = "let x = 1\n# the first line\n#\n# the second line\ndef f [] { ls }\nexport module m {\n# doc of g\n   # more\nexport def g [] { ls }\n\n  # doc of h\n  export def h [] {\n    # inside\n    ls\n  }\n}";
+        let expected = "let x = 1\n# the first line\n#\n# the second line\ndef f [] {ls}\nexport module m {\n    # doc of g\n    # more\n    export def g [] {ls}\n\n    # doc of h\n    export def h [] {\n        # inside\n        ls\n    }\n}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_modules() {
+        let input = "module m {\n  export def g [] { ls }\n}\nmodule n {}";
+        let expected = "module m {export def g [] {ls}}\nmodule n {}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn trim_trailing_whitespace_of_every_line() {
+        let input = "let x = 1\nprint $x\n# a comment\nlet s = \"a  \n  b\"\nls --long $x";
+        let expected = "let x = 1\nprint $x\n# a comment\nlet s = \"a  \n  b\"\nls --long $x";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_redirections() {
+        let input = "ls o>   out.txt\nls   e> err.txt\nls o+e>  all.txt\nls o>>   out.txt\nls e>|grep x\nls o+e>|   grep x";
+        let expected = "ls o> out.txt\nls e> err.txt\nls o+e> all.txt\nls o>> out.txt\nls e>| grep x\nls o+e>| grep x";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn leave_the_words_spelled_like_literals_as_they_are() {
+        // only the lowercase forms are literals, `TRUE` and `Null` are bare words, i.e. strings
+        let input = "let a = [true false null]\nlet b = \"TRUE Null\"\nlet c = [TRUE Null]";
+        run_test(input, input);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        // the bytes of a binary literal written as they are, instead of as hexadecimal digits
+        let formatted = b"let x = 0x[\xff\xfe]".to_vec();
+
+        assert!(matches!(
+            into_string(formatted),
+            Err(FormatError::InvalidUtf8 { offset: 11 })
+        ));
+    }
+
+    #[test]
+    fn format_row_conditions_and_filters() {
+        let input = "ls | where   size  >  10kb  and  name =~ \"a\"\nls | where ( type == dir ) | get name\nls | where $it.size > 1\n[1 2] | each {|x|   $x * 2 }\n[1 2] | reduce -f 0 {|it, acc| $acc + $it }\n[1 2] | filter {|x| $x > 1 } | any {|x| $x == 2 }";
+        let expected = "ls | where size > 10kb and name =~ \"a\"\nls | where (type == dir) | get name\nls | where $it.size > 1\n[1 2] | each {|x| $x * 2}\n[1 2] | reduce -f 0 {|it, acc| $acc + $it}\n[1 2] | filter {|x| $x > 1} | any {|x| $x == 2}";
+        run_test(input, expected);
+
+        // without spaces, the condition is a single word, spacing it would change its meaning
+        run_test("ls | where size>10kb", "ls | where size>10kb");
+    }
+
+    #[test]
+    fn format_try_catch() {
+        let input = "try { ls } catch { print no }\ntry { ls } catch {|err|print $err.msg }\ntry {\n  ls\n  # c\n  ls -la\n}   catch   {|err|\n  print $err\n  # d\n}";
+        let expected = "try {ls} catch {print no}\ntry {ls} catch {|err| print $err.msg}\ntry {\n    ls\n    # c\n    ls -la\n} catch {|err|\n    print $err\n    # d\n}";
+        run_test(input, expected);
+
+        // the parameter of the catch closure is spaced from its body, whatever the source
+        let input = "try { ls } catch {|err|   print $err }\ntry { ls } catch {|| print no }";
+        let expected = "try {ls} catch {|err| print $err}\ntry {ls} catch {|| print no}";
+        run_test(input, expected);
+
+        // a `catch` on the next line is a command of its own, not the one of `try`
+        run_test("try {ls}\ncatch {print no}", "try {ls}\ncatch {print no}");
+    }
+
+    #[test]
+    fn keep_else_if_chains_flat() {
+        let input = "def f [] {\n  if $in == 1 {\n    ls\n    # a\n  } else if $in == 2 {\n    ls -la\n    # b\n  }   else   if $in == 3 {\n    ls -l\n    # c\n  } else {\n    ls -a\n    # d\n  }\n}";
+        let expected = "def f [] {\n    if $in == 1 {\n        ls\n        # a\n    } else if $in == 2 {\n        ls -la\n        # b\n    } else if $in == 3 {\n        ls -l\n        # c\n    } else {\n        ls -a\n        # d\n    }\n}";
+        run_test(input, expected);
+
+        // every `else if` stays at the indentation of the `if`, pass after pass
+        let config = Config::default();
+        let once = format_string(expected, &config).unwrap();
+        assert_eq!(format_string(&once, &config).unwrap(), expected);
+    }
+
+    #[test]
+    fn format_empty_blocks() {
+        let input =
+            "do {  }\nlet f = {||}\nif true {ls} else {   }\ndef g [] {\n  ls\n  do {||  }\n}";
+        let compact =
+            "do {}\nlet f = {||}\nif true {ls} else {}\ndef g [] {\n    ls\n    do {||}\n}";
+        run_test(input, compact);
+
+        let config = Config {
+            empty_block_style: config::EmptyBlockStyle::Spaced,
+            ..Config::default()
+        };
+        let spaced =
+            "do { }\nlet f = {|| }\nif true {ls} else { }\ndef g [] {\n    ls\n    do {|| }\n}";
+        run_test_with_config(input, spaced, &config);
+    }
+
+    #[test]
+    fn keep_the_comments_of_an_otherwise_empty_block() {
+        let input = "if true {\n# nothing yet\n} else {  # why\n}\ndef g [] {\n  ls\n  do {||\n    # later\n  }\n}";
+        let expected = "if true {\n    # nothing yet\n} else { # why\n}\ndef g [] {\n    ls\n    do {||\n        # later\n    }\n}";
+        run_test(input, expected);
+
+        let config = Config {
+            empty_block_style: config::EmptyBlockStyle::Spaced,
+            ..Config::default()
+        };
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn normalize_the_colons_of_records() {
+        let attached = Config {
+            record_colon_spacing: config::RecordColonSpacing::Attached,
+            ..Config::default()
+        };
+        for input in ["{a:1}", "{a :1}", "{a : 1}", "{a:  1}"] {
+            run_test(input, "{a: 1}");
+            run_test_with_config(input, "{a:1}", &attached);
+        }
+
+        let input = "let r = {\"b\" : {c :2}, (1):3}";
+        run_test(input, "let r = {\"b\": {c: 2}, (1): 3}");
+        run_test_with_config(input, "let r = {\"b\":{c:2},(1):3}", &attached);
+
+        // a record written on several lines
+        let input = "{\n  a :1 # first\n  b : 2\n}";
+        run_test(input, "{\n    a: 1 # first\n    b: 2\n}");
+        run_test_with_config(input, "{\n    a:1 # first\n    b:2\n}", &attached);
+    }
+
+    #[test]
+    fn keep_units_attached_to_their_value() {
+        let input = "let a = 10kb\nlet b = [-1.5kb   1.5kb 3sec 0x10b]\nlet c = (-2.5min)";
+        run_test(
+            input,
+            "let a = 10kb\nlet b = [-1.5kb 1.5kb 3sec 0x10b]\nlet c = (-2.5min)",
+        );
+
+        // a space between a value and its unit is invalid code
+        assert!(format_string("let a = 10 kb", &Config::default()).is_err());
+    }
+
+    #[test]
+    fn format_aliases() {
+        let input = "alias  ll  =  ls -l\nexport   alias la =   ls   -a --long\nalias g = git log --oneline  \"a  b\"|head -n 3";
+        let expected = "alias ll = ls -l\nexport alias la = ls -a --long\nalias g = git log --oneline \"a  b\" | head -n 3";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_deeply_nested_structures() {
+        // lists and records that fit on their line stay on it, however deep
+        let input = "let x = [ { name: \"a\", items: [ { id: 1, tags: [ x y z ] } ] } ]";
+        let expected = "let x = [{name: \"a\", items: [{id: 1, tags: [x y z]}]}]";
+        run_test(input, expected);
+
+        // the ones that don't are expanded, down to the ones that fit on their own line
+        let input = "let x = [ { name: \"alpha\", items: [ { id: 1, tags: [ x y z ], owner: { first: \"Ada\", last: \"Lovelace\" } } ] }, { name: \"b\", items: [] } ]";
+        let expected = "let x = [\n    {\n        name: \"alpha\"\n        items: [{id: 1, tags: [x y z], owner: {first: \"Ada\", last: \"Lovelace\"}}]\n    }\n    {name: \"b\", items: []}\n]";
+        run_test(input, expected);
+        let config = Config {
+            max_width: 40,
+            ..Config::default()
+        };
+        let expected = "let x = [\n    {\n        name: \"alpha\"\n        items: [\n            {\n                id: 1\n                tags: [x y z]\n                owner: {\n                    first: \"Ada\"\n                    last: \"Lovelace\"\n                }\n            }\n        ]\n    }\n    {name: \"b\", items: []}\n]";
+        run_test_with_config(input, expected, &config);
+
+        // a record with a comment is on several lines, and so are the collections around it
+        let input = "let x = [{a: 1, # first\n b: 2}, 3]";
+        let expected = "let x = [\n    {\n        a: 1 # first\n        b: 2\n    }\n    3\n]";
+        run_test(input, expected);
+
+        // a table with a row per line puts the tables around it on several lines too
+        let input = "let t = [[a b]; [[[x]; [1] [2]] {c: [[y]; [3]]}]]";
+        let expected = "let t = [\n    [a b];\n    [[\n        [x];\n        [1]\n        [2]\n    ] {c: [[y]; [3]]}]\n]";
+        run_test(input, expected);
+
+        let config = Config::default();
+        let once = format_string(expected, &config).unwrap();
+        assert_eq!(format_string(&once, &config).unwrap(), expected);
+
+        // the pipeline of a table on several lines is as wide as when it was on a single one
+        let input = "let t = ([[a b]; [1 \"a\"] [2 \"b\"]] | dfr into-lazy)";
+        let expected = "let t = ([\n    [a b];\n    [1 \"a\"]\n    [2 \"b\"]\n] | dfr into-lazy)";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_a_lone_expression() {
+        let config = Config::default();
+        let format = |input| format_expression_str(input, &config).unwrap();
+
+        assert_eq!(format("  { a: 1, b: [1 2] }\n"), "{a: 1, b: [1 2]}");
+        assert_eq!(format("ls   |  get   name\n\n"), "ls | get name");
+        assert_eq!(format("{|x|   $x  +  1 }"), "{|x| $x + 1}");
+    }
+
+    #[test]
+    fn a_lone_invalid_expression_is_located_in_the_input() {
+        let err = format_expression_str("  let = 1", &Config::default()).unwrap_err();
+        assert!(
+            matches!(err, FormatError::GarbageFound { span_start, .. } if span_start >= 2),
+            "unexpected error: {:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn trim_the_lines_of_a_file_of_comments() {
+        let config = Config::default();
+        let input = b"# first  \n\n\n\n# second\t\n  # third   \n\n\n";
+        let expected = b"# first\n\n# second\n  # third\n";
+
+        let formatted = format_file_contents(input, &config).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&formatted),
+            String::from_utf8_lossy(expected)
+        );
+        assert!(is_formatted(&formatted, &config).unwrap());
+    }
+
+    #[test]
+    fn break_long_calls_in_subexpressions() {
+        let input = "let files = (ls --all --long --du --short-names --full-paths --threads --mime-type --directory)\n(ls   -a)\n(ls)";
+        let expected = "let files = (\n    ls\n        --all\n        --long\n        --du\n        --short-names\n        --full-paths\n        --threads\n        --mime-type\n        --directory\n)\n(ls -a)\n(ls)";
+        run_test(input, expected);
+
+        let config = Config::default();
+        let once = format_string(expected, &config).unwrap();
+        assert_eq!(format_string(&once, &config).unwrap(), expected);
+    }
+
+    #[test]
+    fn write_a_file_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.nu");
+        std::fs::write(&path, "let x  =  1\n").unwrap();
+
+        assert!(format_single_file(&path, &Config::default()).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "let x = 1\n");
+        // the temporary file is gone
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn leave_out_the_code_of_the_modules_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("helpers.nu");
+        std::fs::write(
+            &module,
+            "export def a [] {\n  (echo 1 2 3)\n  (echo 4 5 6 7 8 9)\n}\n",
+        )
+        .unwrap();
+
+        // a module given by a relative path is never read
+        run_test(
+            "use ./helpers.nu *\n(echo   1)",
+            "use ./helpers.nu *\n(echo 1)",
+        );
+        let input = format!("use '{}' *\n(echo   1)", module.display());
+        let expected = format!("use '{}' *\n(echo 1)", module.display());
+        run_test(&input, &expected);
+    }
+
+    #[test]
+    fn report_the_files_that_cannot_be_read_or_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.nu");
+        let err = format_single_file(&missing, &Config::default()).unwrap_err();
+        assert!(matches!(err, FormatError::Io(_)));
+
+        // a directory can't be replaced by the temporary file, which is removed
+        let directory = dir.path().join("directory.nu");
+        std::fs::create_dir(&directory).unwrap();
+        assert!(write_file(&directory, b"let x = 1\n").is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_the_permissions_of_a_file_written() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.nu");
+        std::fs::write(&path, "let x  =  1\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o754)).unwrap();
+
+        assert!(format_single_file(&path, &Config::default()).unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o754);
+    }
+
+    #[test]
+    fn space_overlays() {
+        let input = "module spam {export def f [] {1}}\noverlay   use   spam\noverlay  use  spam   as   eggs\noverlay   new   scope\noverlay   hide   scope\noverlay use spam   --prefix   --reload";
+        let expected = "module spam {export def f [] {1}}\noverlay use spam\noverlay use spam as eggs\noverlay new scope\noverlay hide scope\noverlay use spam --prefix --reload";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_signature_depends_on_the_config() {
+        let default = format_signature(&Config::default());
+        assert_eq!(default, format_signature(&Config::default()));
+        assert_ne!(
+            default,
+            format_signature(&Config::builder().indent(2).build())
+        );
+        assert_ne!(
+            default,
+            format_signature(&Config::builder().excludes(vec!["a".to_string()]).build())
+        );
+        assert!(!FORMATTER_VERSION.is_empty());
+    }
+
+    #[test]
+    fn format_string_is_stable() {
+        let input = "let  x = [1  2]\ndef f [] { $x }";
+        let formatted = format_string(input, &Config::default()).unwrap();
+        assert_eq!(formatted, "let x = [1 2]\ndef f [] {$x}");
+        for _ in 0..3 {
+            assert_eq!(format_string(input, &Config::default()).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn space_inside_braces() {
+        let config = Config {
+            space_inside_braces: true,
+            ..Config::default()
+        };
+        let input = "let r = {a: 1, b: {c: 2}, d: {}}\ndef foo [] {1}";
+        let expected = "let r = { a: 1, b: { c: 2 }, d: {} }\ndef foo [] { 1 }";
+        run_test_with_config(input, expected, &config);
+
+        let expected = "let r = {a: 1, b: {c: 2}, d: {}}\ndef foo [] {1}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn normalize_binary_literals() {
+        let input = "[0x[AA  BB   cc]  0b[ 1111\n  0000 ]  0o[]]";
+        let expected = "[0x[AA BB cc] 0b[1111 0000] 0o[]]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn format_string_interpolations() {
+        let input = "def foo [x] { $\"outer (  $'inner ( $x )'  ) and (  $x  ) end\\n\" }";
+        let expected = "def foo [x] {$\"outer ($'inner ($x)') and ($x) end\\n\"}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_multiline_strings_in_records() {
+        let input = "let r = {a: 'line1\n   line2', b: \"x\n  y\"}";
+        let expected = "let r = {a: 'line1\n   line2', b: \"x\n  y\"}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn keep_small_tables_on_one_line() {
+        let input = "let t = [[a,  b]; [1,   2]]";
+        let expected = "let t = [[a, b]; [1, 2]]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn write_tables_with_one_row_per_line() {
+        let input = "let t = [[name size]; [foo 1] [bar 2],  [baz 3]\n[qux 4] [quux 5]]";
+        let expected = "let t = [
+    [name size];
+    [foo 1]
+    [bar 2]
+    [baz 3]
+    [qux 4]
+    [quux 5]
+]";
+        run_test(input, expected);
+
+        let input =
+            "let t = [[a_very_long_column_name another_very_long_column_name third]; [1 2 3]]";
+        let expected = "let t = [
+    [a_very_long_column_name another_very_long_column_name third];
+    [1 2 3]
+]";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn space_import_patterns() {
+        let input = "use std  [c   a]";
+        let expected = "use std [c a]";
+        run_test(input, expected);
+
+        run_test("use foo *", "use foo *");
+        run_test("export use foo [b, a]", "export use foo [b, a]");
+        run_test("overlay use foo as bar", "overlay use foo as bar");
+    }
+
+    #[test]
+    fn sort_imports() {
+        let config = Config {
+            sort_imports: true,
+            ..Config::default()
+        };
+        run_test_with_config("use std [c a b a]", "use std [a b c]", &config);
+        run_test_with_config("export use foo [b, a]", "export use foo [a, b]", &config);
+        run_test_with_config("use foo *", "use foo *", &config);
+
+        let input =
+            "module foo {export def a [] {1}; export def b [] {2}}\nuse foo [b a]\nhide foo [b a]";
+        let expected =
+            "module foo {\n    export def a [] {1}\n    export def b [] {2}\n}\nuse foo [a b]\nhide foo [a b]";
+        run_test_with_config(input, expected, &config);
+
+        let input = "use std [c a # not sorted\nb]";
+        run_test_with_config(input, input, &config);
+    }
+
+    #[test]
+    fn limit_consecutive_blank_lines() {
+        let input = "def a [] {1}\n\n\n\n\ndef b [] {2}\n# comment\n\n\n\n\ndef c [] {3}";
+        let cases = [
+            (0, "def a [] {1}\ndef b [] {2}\n# comment\ndef c [] {3}"),
+            (1, "def a [] {1}\n\ndef b [] {2}\n# comment\n\ndef c [] {3}"),
+            (
+                2,
+                "def a [] {1}\n\n\ndef b [] {2}\n# comment\n\n\ndef c [] {3}",
+            ),
+        ];
+
+        for (max_blank_lines, expected) in cases {
+            let config = Config {
+                max_blank_lines,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn limit_the_blank_lines_between_collection_items() {
+        let input = "{\n\n  a: 1 # first\n\n\n\n\n  b: [\n    2 # second\n\n\n\n\n    # third\n    3\n\n  ]\n}";
+        let cases = [
+            (
+                0,
+                "{\n    a: 1 # first\n    b: [\n        2 # second\n        # third\n        3\n    ]\n}",
+            ),
+            (
+                1,
+                "{\n    a: 1 # first\n\n    b: [\n        2 # second\n\n        # third\n        3\n    ]\n}",
+            ),
+            (
+                2,
+                "{\n    a: 1 # first\n\n\n    b: [\n        2 # second\n\n\n        # third\n        3\n    ]\n}",
+            ),
+        ];
+
+        for (max_blank_lines, expected) in cases {
+            let config = Config {
+                max_blank_lines,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn trailing_comma_after_table_rows() {
+        let input = "let t = [[a b]; [1 2], [3 4] [5 6],]";
+        let cases = [
+            (
+                config::TrailingComma::Never,
+                "let t = [\n    [a b];\n    [1 2]\n    [3 4]\n    [5 6]\n]",
+            ),
+            (
+                config::TrailingComma::Always,
+                "let t = [\n    [a b];\n    [1 2],\n    [3 4],\n    [5 6],\n]",
+            ),
+            (
+                config::TrailingComma::Preserve,
+                "let t = [\n    [a b];\n    [1 2],\n    [3 4]\n    [5 6],\n]",
+            ),
+        ];
+
+        for (trailing_comma, expected) in cases {
+            let config = Config {
+                trailing_comma,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn trailing_comma_after_collection_items() {
+        let input = "{\n  a: 1, # first\n  b: [2 # second\n  3]\n  c: 4\n}";
+        let cases = [
+            (
+                config::TrailingComma::Never,
+                "{\n    a: 1 # first\n    b: [\n        2 # second\n        3\n    ]\n    c: 4\n}",
+            ),
+            (
+                config::TrailingComma::Always,
+                "{\n    a: 1, # first\n    b: [\n        2, # second\n        3,\n    ],\n    c: 4,\n}",
+            ),
+            (
+                config::TrailingComma::Preserve,
+                "{\n    a: 1, # first\n    b: [\n        2 # second\n        3\n    ]\n    c: 4\n}",
+            ),
+        ];
+
+        for (trailing_comma, expected) in cases {
+            let config = Config {
+                trailing_comma,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn no_trailing_comma_in_inline_collections() {
+        let input = "let x = {a: {selected_column: {},}, b: [1 2,], c: 3,}";
+        let expected = "let x = {a: {selected_column: {}}, b: [1 2], c: 3}";
+        for trailing_comma in [
+            config::TrailingComma::Never,
+            config::TrailingComma::Always,
+            config::TrailingComma::Preserve,
+        ] {
+            let config = Config {
+                trailing_comma,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn limit_the_pipelines_of_inline_closures() {
+        let config = Config {
+            closure_inline_max_elements: 1,
+            ..Config::default()
+        };
+        run_test_with_config("let f = {|x| [$x $x]}", "let f = {|x| [$x $x]}", &config);
+
+        let input = "let f = {|x| let y = [$x]; let z = [$y]; [$y $z] }";
+        let expected = "let f = {|x|\n    let y = [$x]\n    let z = [$y]\n    [$y $z]\n}";
+        run_test_with_config(input, expected, &config);
+        run_test("let f = {|x| [$x $x]}", "let f = {|x| [$x $x]}");
+
+        // the parameters are attached to the `{`, and spaced from the body
+        let input = "let f = { |x|$x }
+let g = {  || 1 }
+let h = { |x| }
+do { |x|
+  print $x
+  print $x
+} 1";
+        let expected = "let f = {|x| $x}
+let g = {|| 1}
+let h = {|x|}
+do {|x|
+    print $x
+    print $x
+} 1";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn set_the_case_of_hexadecimal_integers() {
+        let input = "[0xff 0xAb_Cd 0o17 0b1010 1_000]";
+        let cases = [
+            (
+                config::IntLiteralCase::Preserve,
+                "[0xff 0xAb_Cd 0o17 0b1010 1_000]",
+            ),
+            (
+                config::IntLiteralCase::Upper,
+                "[0xFF 0xAB_CD 0o17 0b1010 1_000]",
+            ),
+            (
+                config::IntLiteralCase::Lower,
+                "[0xff 0xab_cd 0o17 0b1010 1_000]",
+            ),
+        ];
+
+        for (int_literal_case, expected) in cases {
+            let config = Config {
+                int_literal_case,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+    }
+
+    #[test]
+    fn blocks_are_laid_out_the_same_whatever_their_source() {
+        let corpus = [
+            "def f [x] {\n  let a = 1\n  let b = [$a $x]\n  [$a $b]\n}",
+            "def f [x] { let a = 1; let b = [$a $x]; [$a $b] }",
+            "let g = {|x| do {|| [$x] } }",
+            "let g = {|x|\n    let y = [$x]\n    do {|| [$y] }\n}",
+            "do {|x| let y = [$x $x $x $x $x $x $x $x $x $x $x $x $x $x $x $x $x $x $x $x]; [$y] }",
+            "def long-command-name [first second third] { [$first $second $third $first] }",
+        ];
+
+        for input in corpus {
+            let once = format_string(input, &Config::default()).unwrap();
+            let twice = format_string(&once, &Config::default()).unwrap();
+            assert_eq!(once, twice, "formatting {input:?} is not idempotent");
+        }
+
+        let expected = "def f [x] {\n    let a = 1\n    let b = [$a $x]\n    [$a $b]\n}";
+        run_test(corpus[0], expected);
+        run_test(corpus[1], expected);
+    }
+
+    #[test]
+    fn put_every_pipeline_of_a_block_on_its_own_line() {
+        let expected = "def foo [a: int] {\n    let b = $a + 1\n    $b * 2\n}";
+        run_test("def foo [a: int] {\n let b = $a + 1\n $b * 2\n}", expected);
+        run_test("def foo [a: int] { let b = $a + 1; $b * 2 }", expected);
+
+        let input = "if true {\n    let a = 1\n    let b = 2\n}\ndo {|x| let y = $x; $y } 1";
+        let expected =
+            "if true {\n    let a = 1\n    let b = 2\n}\ndo {|x|\n    let y = $x\n    $y\n} 1";
+        run_test(input, expected);
+
+        // a block with a single pipeline stays inline
+        let input = "module m {\n  export def q [] {\n    1\n    2\n  }\n  export def r [] {\n    3\n  }\n}";
+        let expected = "module m {\n    export def q [] {\n        1\n        2\n    }\n    export def r [] {3}\n}";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn align_trailing_comments() {
+        let input = "let a = [1]   # first\nlet bb = [1 2] # second\nlet ccc = [1 2 3]  # third";
+        run_test(
+            input,
+            "let a = [1] # first\nlet bb = [1 2] # second\nlet ccc = [1 2 3] # third",
+        );
+
+        let config = Config {
+            align_trailing_comments: true,
+            ..Config::default()
+        };
+        let expected =
+            "let a = [1]       # first\nlet bb = [1 2]    # second\nlet ccc = [1 2 3] # third";
+        run_test_with_config(input, expected, &config);
+
+        let config = Config {
+            align_trailing_comments: true,
+            max_width: 25,
+            ..Config::default()
+        };
+        let expected = "let a = [1] # first\nlet bb = [1 2] # second\nlet ccc = [1 2 3] # third";
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn place_the_pipes_of_long_pipelines() {
+        let input =
+            "^git log --oneline --decorate --all --graph --max-count 200 | lines | first 3 | sort";
+        let cases = [
+            (
+                config::PipePlacement::Leading,
+                "^git log --oneline --decorate --all --graph --max-count 200\n    | lines\n    | first 3\n    | sort",
+            ),
+            (
+                config::PipePlacement::Trailing,
+                "^git log --oneline --decorate --all --graph --max-count 200 |\n    lines |\n    first 3 |\n    sort",
+            ),
+        ];
+
+        for (pipe_placement, expected) in cases {
+            let config = Config {
+                pipe_placement,
+                ..Config::default()
+            };
+            run_test_with_config(input, expected, &config);
+        }
+
+        run_test("^git log\n  | lines |\n  sort", "^git log | lines | sort");
+    }
+
+    #[test]
+    fn split_pipelines_separated_by_semicolons() {
+        let input = "let x = 1; let y = [$x]\n^git status\ndef f [] {\n  let a = [1]; let b = [$a]\n  [$a $b]\n}";
+        let expected = "let x = 1\nlet y = [$x]\n^git status\ndef f [] {\n    let a = [1]\n    let b = [$a]\n    [$a $b]\n}";
+        run_test(input, expected);
+
+        let config = Config {
+            split_semicolons: false,
+            ..Config::default()
+        };
+        let expected = "let x = 1; let y = [$x]\n^git status\ndef f [] {\n    let a = [1]; let b = [$a]\n    [$a $b]\n}";
+        run_test_with_config(input, expected, &config);
+
+        // the pipelines of nested blocks are split too
+        let input = "for i in [1 2] {\n    if true {\n        let a = 1\n        let b = 2\n    }\n    do {|| let c = 3; $c }\n}";
+        let expected = "for i in [1 2] {\n    if true {\n        let a = 1\n        let b = 2\n    }\n    do {||\n        let c = 3\n        $c\n    }\n}";
+        run_test(input, expected);
+        let expected = "for i in [1 2] {\n    if true {\n        let a = 1\n        let b = 2\n    }\n    do {||\n        let c = 3; $c\n    }\n}";
+        run_test_with_config(input, expected, &config);
+
+        // the `;` before a comment stays attached to the code
+        let input =
+            "let a = 1; # comment\ndef f [] {\n  let b = 2 ;  # b\n  $b\n}\nlet c = 3;   # c";
+        let expected =
+            "let a = 1; # comment\ndef f [] {\n    let b = 2; # b\n    $b\n}\nlet c = 3; # c";
+        run_test(input, expected);
+        run_test_with_config(input, expected, &config);
+    }
+
+    #[test]
+    fn write_ranges_without_spaces() {
+        let input = "[1..10  0..<5  1..2..10  5..  ..5  (1)..( 3 )]";
+        let expected = "[1..10 0..<5 1..2..10 5.. ..5 (1)..(3)]";
+        run_test(input, expected);
+
+        let input = "def f [a b] { [$a..$b $a..2..<$b] }";
+        let expected = "def f [a b] {[$a..$b $a..2..<$b]}";
+        run_test(input, expected);
     }
 
     #[test]
@@ -62,7 +1657,7 @@ mod test {
     \"a\": null
   }
 ]";
-        let expected = "[{\"a\":0},{},{\"a\":null}]";
+        let expected = "[{\"a\": 0},{},{\"a\": null}]";
         run_test(input, expected);
     }
 
@@ -100,12 +1695,14 @@ myfunc(one)
 
 ";
         let expected = "# beginning of script comment
+
 let one = 1
 def my-func [
     param1:int # inline comment
-]{ print(param1) 
+]{ print(param1)
 }
-myfunc(one) 
+myfunc(one)
+
 # final comment";
         run_test(input, expected);
     }
@@ -124,6 +1721,51 @@ myfunc(one)
         run_test(input, expected);
     }
 
+    #[test]
+    fn keep_shebang_on_first_line() {
+        let input = "#!/usr/bin/env nu\n\nlet one = 1";
+        let expected = input;
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn separate_shebang_from_code_with_a_blank_line() {
+        let expected = "#!/usr/bin/env nu\n\nlet one = 1";
+        run_test("#!/usr/bin/env nu\nlet one = 1", expected);
+        run_test("#!/usr/bin/env nu\n\n\n\nlet one = 1", expected);
+
+        let expected = "#!/usr/bin/env nu\n\n# the first number\nlet one = 1";
+        run_test(
+            "#!/usr/bin/env nu\n\n\n# the first number\nlet one = 1",
+            expected,
+        );
+    }
+
+    #[test]
+    fn keep_comment_attached_to_shebang() {
+        let input = "#!/usr/bin/env nu\n# the first number\nlet one = 1";
+        run_test(input, input);
+    }
+
+    #[test]
+    fn keep_shebang_without_code() {
+        let input = "#!/usr/bin/env nu\n";
+        let expected = "#!/usr/bin/env nu";
+        run_test(input, expected);
+    }
+
+    #[test]
+    fn no_leading_newline_without_shebang() {
+        let input = "def my-func [] { 1 }";
+        let formatted = format_string(input, &Config::default()).unwrap();
+
+        assert!(formatted.starts_with("def my-func"));
+        assert_eq!(
+            formatted,
+            format_string(&formatted, &Config::default()).unwrap()
+        );
+    }
+
     #[test]
     fn remove_leading_whitespace() {
         let input = "   0";
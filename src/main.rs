@@ -1,17 +1,91 @@
 #![doc = include_str!("../README.md")]
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use log::{error, info, trace};
-use nu_formatter::config::Config;
+use nu_ansi_term::{Color, Style};
+use nu_formatter::{
+    config::{Config, ConfigError},
+    FileDiagnostic, FormatError, Mode, SearchOptions,
+};
+use nu_glob::{Pattern, PatternError};
+use nu_protocol::{
+    ast::PipelineElement,
+    engine::{EngineState, StateWorkingSet},
+    eval_const::eval_constant,
+    Record, Span, Value,
+};
+use serde_json::json;
+use similar::TextDiff;
 use std::{
+    convert::TryFrom,
     fs,
-    io::Write,
+    io::{IsTerminal, Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 
+/// the names of the configuration files `nufmt` looks for, by order of preference
+const CONFIG_FILE_NAMES: [&str; 2] = ["nufmt.nuon", "nufmt.toml"];
+
 enum ExitCode {
     Success,
     Failure,
+    /// the input could not even be given to the formatter
+    Exception,
+}
+
+/// how the results of `nufmt` are printed
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// messages meant to be read by humans
+    Human,
+    /// a JSON array with the status of every file
+    Json,
+}
+
+/// a range of 1-based lines, both included
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+
+/// parse a `<START>:<END>` range of lines
+fn parse_line_range(range: &str) -> Result<LineRange, String> {
+    let Some((start, end)) = range.split_once(':') else {
+        return Err(format!("`{range}` is not of the form <START>:<END>"));
+    };
+    let parse_line = |line: &str| match line.parse::<usize>() {
+        Ok(line) if line > 0 => Ok(line),
+        _ => Err(format!("`{line}` is not a line number")),
+    };
+
+    let (start, end) = (parse_line(start)?, parse_line(end)?);
+    if start > end {
+        return Err(format!("the range {range} ends before it starts"));
+    }
+    Ok(LineRange { start, end })
+}
+
+/// what happened to a file given to `nufmt`
+#[derive(Debug, PartialEq)]
+enum FileStatus {
+    AlreadyFormatted,
+    Reformatted,
+    WouldReformat,
+    /// the file could not be formatted, for the given reason
+    Failure(String),
+}
+
+impl FileStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            FileStatus::AlreadyFormatted => "already_formatted",
+            FileStatus::Reformatted => "reformatted",
+            FileStatus::WouldReformat => "would_reformat",
+            FileStatus::Failure(_) => "failure",
+        }
+    }
 }
 
 /// the CLI signature of the `nufmt` executable.
@@ -19,25 +93,139 @@ enum ExitCode {
 #[command(author, version, about)]
 struct Cli {
     #[arg(
-        required_unless_present("stdin"),
+        required_unless_present_any(["stdin", "files_from", "show_config", "print_default_config"]),
         help = "one of more Nushell files you want to format"
     )]
     files: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "stdin",
+        help = "also format the files listed in FILE, one path per line, `#` starting a comment line"
+    )]
+    files_from: Option<PathBuf>,
     #[arg(
         short,
         long,
         conflicts_with = "files",
-        help = "a string of Nushell directly given to the formatter"
+        help = "read the Nushell code from stdin and write the formatted code to stdout"
+    )]
+    stdin: bool,
+    #[arg(
+        long,
+        requires = "stdin",
+        conflicts_with = "files",
+        value_name = "PATH",
+        help = "the path of the file read from stdin, the configuration is looked for from its directory"
+    )]
+    stdin_filepath: Option<PathBuf>,
+    #[arg(
+        short,
+        long,
+        help = "the configuration file, by default the closest nufmt.nuon or nufmt.toml is used"
     )]
-    stdin: Option<String>,
-    #[arg(short, long, help = "the configuration file")]
     config: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "NUON",
+        conflicts_with = "config",
+        help = "the configuration as a NUON record, e.g. '{indent: 2}', instead of a configuration file"
+    )]
+    config_inline: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["config", "config_inline"],
+        help = "use the default configuration, ignoring any nufmt.nuon or nufmt.toml"
+    )]
+    no_config: bool,
+    #[arg(
+        long,
+        help = "print where the configuration comes from and its options as NUON, without formatting"
+    )]
+    show_config: bool,
+    #[arg(
+        long,
+        help = "print a nufmt.nuon with every option set to its default value, without formatting"
+    )]
+    print_default_config: bool,
+    #[arg(
+        long,
+        visible_alias = "dry-run",
+        conflicts_with = "stdin",
+        help = "check the files without modifying them, exits with 1 if any of them would be reformatted"
+    )]
+    check: bool,
+    #[arg(
+        long,
+        help = "print a diff of the changes instead of applying them, fails if anything would change"
+    )]
+    diff: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Human,
+        conflicts_with_all = ["stdin", "diff"],
+        help = "how to print the results, `json` prints the status of every file"
+    )]
+    output_format: OutputFormat,
+    #[arg(
+        long,
+        value_name = "START:END",
+        value_parser = parse_line_range,
+        requires = "stdin",
+        conflicts_with = "files",
+        help = "only format the code of stdin between these 1-based lines, both included"
+    )]
+    range: Option<LineRange>,
+    #[arg(
+        short,
+        long,
+        visible_alias = "parallel",
+        value_name = "N",
+        help = "the number of files formatted at the same time, by default one per CPU core"
+    )]
+    jobs: Option<NonZeroUsize>,
+    #[arg(
+        short,
+        long,
+        help = "do not print how many files were already formatted or have been formatted"
+    )]
+    quiet: bool,
+    #[arg(
+        long,
+        help = "print plain text, without colors, which is also the case when NO_COLOR is set or the output is not a terminal"
+    )]
+    no_color: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["stdin", "check", "diff"],
+        help = "copy the original contents of every file reformatted to <FILE>.bak before writing it"
+    )]
+    backup: bool,
+    #[arg(
+        long,
+        conflicts_with = "stdin",
+        help = "also format the ```nu code blocks of the Markdown files, the .md files of the directories included"
+    )]
+    markdown: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "only look for files N levels deep in the directories, 1 for the files right in them"
+    )]
+    max_depth: Option<NonZeroUsize>,
+    #[arg(
+        long,
+        help = "follow the symbolic links found in the directories, which are ignored by default"
+    )]
+    follow_symlinks: bool,
 }
 
-fn exit_with_code(exit_code: ExitCode) {
+fn exit_with_code(exit_code: ExitCode) -> ! {
     let code = match exit_code {
         ExitCode::Success => 0,
         ExitCode::Failure => 1,
+        ExitCode::Exception => 2,
     };
     trace!("exit code: {code}");
 
@@ -47,26 +235,136 @@ fn exit_with_code(exit_code: ExitCode) {
 }
 
 fn main() {
-    env_logger::init();
-
     let cli = Cli::parse();
+    let color = use_colors(
+        cli.no_color,
+        std::env::var_os("NO_COLOR").as_deref(),
+        std::io::stdout().is_terminal(),
+    );
+    env_logger::Builder::from_default_env()
+        .write_style(match color {
+            true => env_logger::WriteStyle::Auto,
+            false => env_logger::WriteStyle::Never,
+        })
+        .init();
+
     trace!("recieved cli.files: {:?}", cli.files);
+    trace!("recieved cli.files_from: {:?}", cli.files_from);
     trace!("recieved cli.stdin: {:?}", cli.stdin);
+    trace!("recieved cli.stdin_filepath: {:?}", cli.stdin_filepath);
     trace!("recieved cli.config: {:?}", cli.config);
+    trace!("recieved cli.config_inline: {:?}", cli.config_inline);
+    trace!("recieved cli.no_config: {:?}", cli.no_config);
+    trace!("recieved cli.show_config: {:?}", cli.show_config);
+    trace!(
+        "recieved cli.print_default_config: {:?}",
+        cli.print_default_config
+    );
 
-    let cli_config = match cli.config {
-        None => Config::default(),
-        Some(input_cli) => {
-            todo!(
-                "cannot read from {:?} Reading a config from file not implemented!",
-                input_cli
-            )
-        }
+    if cli.print_default_config {
+        println!("{}", Config::default().to_nuon());
+        exit_with_code(ExitCode::Success);
+    }
+    trace!("recieved cli.check: {:?}", cli.check);
+    trace!("recieved cli.diff: {:?}", cli.diff);
+    trace!("recieved cli.output_format: {:?}", cli.output_format);
+    trace!("recieved cli.range: {:?}", cli.range);
+    trace!("recieved cli.jobs: {:?}", cli.jobs);
+    trace!("recieved cli.quiet: {:?}", cli.quiet);
+    trace!("recieved cli.no_color: {:?}", cli.no_color);
+    trace!("recieved cli.markdown: {:?}", cli.markdown);
+    trace!("recieved cli.backup: {:?}", cli.backup);
+    trace!("recieved cli.max_depth: {:?}", cli.max_depth);
+    trace!("recieved cli.follow_symlinks: {:?}", cli.follow_symlinks);
+
+    let config_path = match (&cli.config_inline, cli.no_config) {
+        (None, false) => config_path(cli.config, cli.stdin_filepath.as_deref()),
+        _ => None,
+    };
+    let config_source = match (&cli.config_inline, &config_path) {
+        _ if cli.no_config => "the default configuration, --no-config".to_string(),
+        (Some(_), _) => "the configuration of --config-inline".to_string(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => "the default configuration, no configuration file found".to_string(),
+    };
+    let loaded_config = match cli.config_inline {
+        _ if cli.no_config => std::env::current_dir()
+            .ok()
+            .map(|current_dir| (Config::default(), current_dir)),
+        Some(nuon) => load_inline_config(&nuon),
+        None => load_config(config_path),
+    };
+    let Some((cli_config, config_dir)) = loaded_config else {
+        exit_with_code(ExitCode::Failure);
     };
+    trace!("using config: {:?}", cli_config);
+    if cli.show_config {
+        println!("# {config_source}\n{}", cli_config.to_nuon());
+        exit_with_code(ExitCode::Success);
+    }
+    if let Err(err) = check_patterns(&cli_config) {
+        error!("Error: invalid config: {err}");
+        exit_with_code(ExitCode::Failure);
+    }
 
-    let exit_code = match cli.files[..] {
-        [] => format_string(cli.stdin, &cli_config),
-        _ => format_files(cli.files, &cli_config),
+    let mut files = cli.files;
+    if let Some(list) = cli.files_from {
+        match fs::read_to_string(&list) {
+            Ok(contents) => files.extend(parse_file_list(&contents)),
+            Err(err) => {
+                error!(
+                    "Error: could not read the file list {}: {err}",
+                    list.display()
+                );
+                exit_with_code(ExitCode::Failure);
+            }
+        }
+    }
+    // the code of a file left out by the configuration is written back as it is
+    let stdin_excluded = cli
+        .stdin_filepath
+        .as_deref()
+        .is_some_and(|path| nu_formatter::is_excluded(&config_dir, path, &cli_config));
+    let files_run = FilesRun {
+        files,
+        root: config_dir,
+        config: cli_config,
+        mode: match (cli.check || cli.diff, cli.backup) {
+            (true, _) => Mode::Check,
+            (false, true) => Mode::Backup,
+            (false, false) => Mode::Write,
+        },
+        search: SearchOptions {
+            markdown: cli.markdown,
+            max_depth: cli.max_depth.map(NonZeroUsize::get),
+            follow_symlinks: cli.follow_symlinks,
+        },
+        show_diff: cli.diff,
+        output_format: cli.output_format,
+        quiet: cli.quiet,
+        color,
+    };
+    let (stdin, range) = (cli.stdin, cli.range);
+    // an empty `--files-from` list formats no file rather than stdin
+    let run = move || match stdin {
+        true if stdin_excluded && files_run.show_diff => ExitCode::Success,
+        true if stdin_excluded => echo_stdin(),
+        true if files_run.show_diff => diff_stdin(&files_run.config, range, color),
+        true => format_stdin(&files_run.config, range),
+        false => format_files(files_run),
+    };
+    let exit_code = match cli.jobs {
+        Some(jobs) => match rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.get())
+            .build()
+        {
+            Ok(pool) => pool.install(run),
+            Err(err) => {
+                error!("Error: could not start {jobs} jobs: {err}");
+                ExitCode::Failure
+            }
+        },
+        None => run(),
     };
 
     std::io::stdout().flush().unwrap();
@@ -74,58 +372,605 @@ fn main() {
     exit_with_code(exit_code);
 }
 
-/// format a string passed via stdin and output it directly to stdout
-fn format_string(string: Option<String>, options: &Config) -> ExitCode {
-    let output = nu_formatter::format_string(&string.unwrap(), options);
-    println!("output: \n{output}");
+/// the paths listed in the contents of a `--files-from` file, one per line
+///
+/// The blank lines and the lines starting with `#` are left out.
+fn parse_file_list(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// the configuration file to use, the one given with `--config` or else the closest one
+///
+/// The closest configuration file is looked for from the directory of `stdin_filepath`
+/// if there is one, from the current directory otherwise.
+/// Returns `None` if there is no configuration file, the default configuration is then used.
+fn config_path(cli_config: Option<PathBuf>, stdin_filepath: Option<&Path>) -> Option<PathBuf> {
+    let current_dir = std::env::current_dir().ok()?;
+    match cli_config {
+        Some(path) => Some(current_dir.join(path)),
+        None => {
+            let search_dir = match stdin_filepath.and_then(|path| path.parent()) {
+                Some(parent) => current_dir.join(parent),
+                None => current_dir,
+            };
+            find_in_parent_dirs(&search_dir)
+        }
+    }
+}
+
+/// read the configuration file at `path`, with the directory its paths are relative to
+///
+/// The paths of the default configuration, if there is no file, are relative to the current
+/// directory.
+/// Returns `None`, after logging the error, if the file is invalid.
+fn load_config(path: Option<PathBuf>) -> Option<(Config, PathBuf)> {
+    let current_dir = std::env::current_dir().ok()?;
+    let Some(path) = path else {
+        return Some((Config::default(), current_dir));
+    };
+    let config_dir = path.parent().map_or(current_dir, Path::to_path_buf);
+    info!("reading config from {}", path.display());
+
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!("Error: could not read config {}: {err}", path.display());
+            return None;
+        }
+    };
+
+    match read_config(&path, &contents) {
+        Ok(config) => Some((config, config_dir)),
+        Err(err) => {
+            error!(
+                "Error: invalid config {}: {}",
+                path.display(),
+                describe_config_error(&contents, &err)
+            );
+            None
+        }
+    }
+}
+
+/// describe what is wrong with a configuration file, pointing to the faulty option
+/// in its `contents` if it is known
+fn describe_config_error(contents: &[u8], err: &ConfigError) -> String {
+    let ConfigError::UnknownOption(key, Some(value_offset)) = err else {
+        return err.to_string();
+    };
+    // the offset is the one of the value, the key is the last time it is written before it
+    let before = &contents[..(*value_offset).min(contents.len())];
+    let offset = before
+        .windows(key.len())
+        .rposition(|window| window == key.as_bytes())
+        .unwrap_or(*value_offset);
+    let (line, column) = line_and_column(contents, offset);
+    let source_line = contents
+        .split(|&byte| byte == b'\n')
+        .nth(line - 1)
+        .unwrap_or_default();
+    format!(
+        "{err} at {line}:{column}\n{}\n{}{}",
+        String::from_utf8_lossy(source_line).trim_end(),
+        " ".repeat(column - 1),
+        "^".repeat(key.chars().count().max(1))
+    )
+}
+
+/// load the configuration given as a NUON record on the command line
+///
+/// Its paths are relative to the current directory.
+/// Returns `None`, after logging the error, if the configuration is invalid.
+fn load_inline_config(nuon: &str) -> Option<(Config, PathBuf)> {
+    let current_dir = std::env::current_dir().ok()?;
+    match nuon_to_value(nuon.as_bytes()).and_then(Config::try_from) {
+        Ok(config) => Some((config, current_dir)),
+        Err(err) => {
+            error!(
+                "Error: invalid inline config: {}",
+                describe_config_error(nuon.as_bytes(), &err)
+            );
+            None
+        }
+    }
+}
+
+/// look for a configuration file in `dir` and all its parents, the closest one wins
+fn find_in_parent_dirs(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().find_map(|dir| {
+        CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+/// parse the `contents` of the configuration file at `path`
+///
+/// The file is read as TOML if it has a `.toml` extension and as NUON otherwise.
+fn read_config(path: &Path, contents: &[u8]) -> Result<Config, ConfigError> {
+    let value = if is_file_extension(path, ".toml") {
+        toml_to_value(contents)?
+    } else {
+        nuon_to_value(contents)?
+    };
+    Config::try_from(value)
+}
+
+/// parse some NUON into a value
+///
+/// This only accepts a single constant expression, e.g. a record of options.
+fn nuon_to_value(contents: &[u8]) -> Result<Value, ConfigError> {
+    let engine_state = EngineState::new();
+    let mut working_set = StateWorkingSet::new(&engine_state);
+    let block = nu_parser::parse(&mut working_set, None, contents, false);
+
+    if let Some(err) = working_set.parse_errors.first() {
+        return Err(ConfigError::InvalidFormat(err.to_string()));
+    }
+
+    let expression = match block.pipelines.as_slice() {
+        [] => return Ok(Value::record(Record::new(), Span::unknown())),
+        [pipeline] => match pipeline.elements.as_slice() {
+            [PipelineElement::Expression(_, expression)] => expression,
+            _ => return Err(ConfigError::NotARecord),
+        },
+        _ => return Err(ConfigError::NotARecord),
+    };
+
+    eval_constant(&working_set, expression)
+        .map_err(|err| ConfigError::InvalidFormat(err.to_string()))
+}
+
+/// parse some TOML into a value, with the same shape as if it had been written in NUON
+fn toml_to_value(contents: &[u8]) -> Result<Value, ConfigError> {
+    let contents =
+        std::str::from_utf8(contents).map_err(|err| ConfigError::InvalidFormat(err.to_string()))?;
+    let table: toml::Table =
+        toml::from_str(contents).map_err(|err| ConfigError::InvalidFormat(err.to_string()))?;
+
+    Ok(convert_toml_value(toml::Value::Table(table)))
+}
+
+fn convert_toml_value(value: toml::Value) -> Value {
+    let span = Span::unknown();
+    match value {
+        toml::Value::String(val) => Value::string(val, span),
+        toml::Value::Integer(val) => Value::int(val, span),
+        toml::Value::Float(val) => Value::float(val, span),
+        toml::Value::Boolean(val) => Value::bool(val, span),
+        toml::Value::Datetime(val) => Value::string(val.to_string(), span),
+        toml::Value::Array(vals) => {
+            Value::list(vals.into_iter().map(convert_toml_value).collect(), span)
+        }
+        toml::Value::Table(table) => {
+            let mut record = Record::new();
+            for (key, val) in table {
+                record.push(key, convert_toml_value(val));
+            }
+            Value::record(record, span)
+        }
+    }
+}
+
+/// read all of stdin as raw bytes, making sure it is valid UTF-8
+///
+/// Returns `None`, after printing the error, if stdin can't be read.
+fn read_stdin() -> Option<String> {
+    let mut input = Vec::new();
+    if let Err(err) = std::io::stdin().read_to_end(&mut input) {
+        eprintln!("error: could not read stdin: {err}");
+        return None;
+    }
+
+    match String::from_utf8(input) {
+        Ok(input) => Some(input),
+        Err(err) => {
+            eprintln!("error: stdin is not valid UTF-8: {}", err.utf8_error());
+            None
+        }
+    }
+}
+
+/// format the code read from stdin, or only its given `range` of lines
+fn format_stdin_contents(
+    input: &[u8],
+    options: &Config,
+    range: Option<LineRange>,
+) -> Result<Vec<u8>, FormatError> {
+    match range {
+        Some(LineRange { start, end }) => nu_formatter::format_range(input, options, start, end),
+        None => nu_formatter::format_file_contents(input, options),
+    }
+}
+
+/// format the code passed via stdin and output it directly to stdout
+fn format_stdin(options: &Config, range: Option<LineRange>) -> ExitCode {
+    let Some(input) = read_stdin() else {
+        return ExitCode::Exception;
+    };
+
+    match format_stdin_contents(input.as_bytes(), options, range) {
+        Ok(output) => {
+            std::io::stdout().write_all(&output).unwrap();
+            ExitCode::Success
+        }
+        Err(err) => {
+            report_error("stdin", input.as_bytes(), &err);
+            ExitCode::Failure
+        }
+    }
+}
 
+/// output the code passed via stdin unchanged, as its `--stdin-filepath` is excluded
+fn echo_stdin() -> ExitCode {
+    let Some(input) = read_stdin() else {
+        return ExitCode::Exception;
+    };
+    std::io::stdout().write_all(input.as_bytes()).unwrap();
     ExitCode::Success
 }
 
-/// format a list of files, possibly one, and modify them inplace
-fn format_files(files: Vec<PathBuf>, options: &Config) -> ExitCode {
-    for file in &files {
-        if !file.exists() {
-            error!("Error: {} not found!", file.to_str().unwrap());
+/// print the diff between the code passed via stdin and its formatted version
+fn diff_stdin(options: &Config, range: Option<LineRange>, color: bool) -> ExitCode {
+    let Some(input) = read_stdin() else {
+        return ExitCode::Exception;
+    };
+
+    let output = match format_stdin_contents(input.as_bytes(), options, range) {
+        Ok(output) => String::from_utf8_lossy(&output).into_owned(),
+        Err(err) => {
+            report_error("stdin", input.as_bytes(), &err);
             return ExitCode::Failure;
-        } else if file.is_dir() {
-            for path in recurse_files(file).unwrap() {
-                if is_file_extension(&path, ".nu") {
-                    info!("formatting file: {:?}", &path);
-                    nu_formatter::format_single_file(&path, options);
-                } else {
-                    info!("not nu file: skipping");
-                }
+        }
+    };
+
+    if input == output {
+        return ExitCode::Success;
+    }
+
+    print_diff("stdin", &input, &output, color);
+    ExitCode::Failure
+}
+
+/// what happened to a single file, with what is needed to tell the user about it
+struct FileReport {
+    path: PathBuf,
+    status: FileStatus,
+    /// the original and the formatted contents of a file that would be reformatted
+    diff: Option<(Vec<u8>, Vec<u8>)>,
+    /// the contents of a file that could not be formatted, and why
+    error: Option<(Vec<u8>, FormatError)>,
+}
+
+impl FileReport {
+    fn new(path: PathBuf, diagnostic: FileDiagnostic) -> Self {
+        let (status, diff) = match diagnostic {
+            FileDiagnostic::AlreadyFormatted => (FileStatus::AlreadyFormatted, None),
+            FileDiagnostic::Reformatted => (FileStatus::Reformatted, None),
+            FileDiagnostic::WouldReformat {
+                original,
+                formatted,
+            } => (FileStatus::WouldReformat, Some((original, formatted))),
+            FileDiagnostic::Failure { contents, error } => {
+                return FileReport::failure(path, contents, error)
             }
-            // Files only
-        } else {
-            info!("formatting file: {:?}", file);
-            nu_formatter::format_single_file(file, options);
+        };
+        FileReport {
+            path,
+            status,
+            diff,
+            error: None,
         }
     }
 
-    ExitCode::Success
+    fn failure(path: PathBuf, contents: Vec<u8>, err: FormatError) -> Self {
+        FileReport {
+            path,
+            status: FileStatus::Failure(failure_reason(&contents, &err)),
+            diff: None,
+            error: Some((contents, err)),
+        }
+    }
 }
 
-fn recurse_files(path: impl AsRef<Path>) -> std::io::Result<Vec<PathBuf>> {
-    let mut buf = vec![];
-    let entries = fs::read_dir(path)?;
+/// print what happened to every file, sorted by path, and compute the exit code
+///
+/// The files are formatted in parallel, so they are only reported once all of them are
+/// done, to always print the messages in the same order.
+/// Unless `quiet` is set, the number of files already formatted, or that have been
+/// formatted, is printed at the end, in color if `color` is set.
+fn display_diagnostic_and_compute_exit_code(
+    mut reports: Vec<FileReport>,
+    checking: bool,
+    show_diff: bool,
+    output_format: OutputFormat,
+    quiet: bool,
+    color: bool,
+) -> ExitCode {
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut exit_code = ExitCode::Success;
+    for report in &reports {
+        let name = report.path.display().to_string();
+        if let Some((contents, err)) = &report.error {
+            report_error(&name, contents, err);
+        }
+        if output_format == OutputFormat::Human {
+            match &report.diff {
+                Some((original, formatted)) if show_diff => print_diff(
+                    &name,
+                    &String::from_utf8_lossy(original),
+                    &String::from_utf8_lossy(formatted),
+                    color,
+                ),
+                Some(_) => println!("Would reformat: {name}"),
+                None => {}
+            }
+        }
+        if matches!(
+            report.status,
+            FileStatus::WouldReformat | FileStatus::Failure(_)
+        ) {
+            exit_code = ExitCode::Failure;
+        }
+    }
+
+    if output_format == OutputFormat::Human && !quiet {
+        if checking {
+            let (line, line_color) = check_summary(&reports);
+            println!("{}", paint(line_color.bold(), &line, color));
+        } else {
+            for line in summary(&reports) {
+                println!("{line}");
+            }
+        }
+    }
 
-    for entry in entries {
-        let entry = entry?;
-        let meta = entry.metadata()?;
+    if output_format == OutputFormat::Json {
+        let statuses: Vec<(PathBuf, FileStatus)> = reports
+            .into_iter()
+            .map(|report| (report.path, report.status))
+            .collect();
+        println!("{}", render_json_report(&statuses));
+    }
+    exit_code
+}
 
-        if meta.is_dir() {
-            let mut subdir = recurse_files(entry.path())?;
-            buf.append(&mut subdir);
+/// log an error that happened while formatting `name`
+///
+/// If the error points to some code, its line and column in `contents` are given.
+fn report_error(name: &str, contents: &[u8], err: &FormatError) {
+    match err {
+        FormatError::GarbageFound {
+            span_start,
+            snippet,
+            ..
+        } => {
+            let (line, column) = line_and_column(contents, *span_start);
+            error!("Error: {name}:{line}:{column}: found invalid code: {snippet}");
         }
+        err => error!("Error: {name}: {err}"),
+    }
+}
 
-        if meta.is_file() {
-            buf.push(entry.path());
+/// describe why some code could not be formatted, without the name of its file
+fn failure_reason(contents: &[u8], err: &FormatError) -> String {
+    match err {
+        FormatError::GarbageFound {
+            span_start,
+            snippet,
+            ..
+        } => {
+            let (line, column) = line_and_column(contents, *span_start);
+            format!("{line}:{column}: found invalid code: {snippet}")
         }
+        err => err.to_string(),
+    }
+}
+
+/// compute the 1-based line and column of the byte at `offset` in `contents`
+fn line_and_column(contents: &[u8], offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.iter().filter(|&&byte| byte == b'\n').count() + 1;
+    let column = match before.iter().rposition(|&byte| byte == b'\n') {
+        Some(newline) => offset - newline,
+        None => offset + 1,
+    };
+    (line, column)
+}
+
+/// render a unified diff between the `original` and the `formatted` contents of `name`
+fn render_diff(name: &str, original: &str, formatted: &str) -> String {
+    TextDiff::from_lines(original, formatted)
+        .unified_diff()
+        .header(
+            &format!("{name} (original)"),
+            &format!("{name} (formatted)"),
+        )
+        .to_string()
+}
+
+/// print the unified diff of `name`, with removed lines in red and added lines in green
+/// if `color` is set
+fn print_diff(name: &str, original: &str, formatted: &str, color: bool) {
+    for line in render_diff(name, original, formatted).lines() {
+        let style = if line.starts_with("---") || line.starts_with("+++") {
+            Color::White.bold()
+        } else if line.starts_with("@@") {
+            Color::Cyan.normal()
+        } else if line.starts_with('-') {
+            Color::Red.normal()
+        } else if line.starts_with('+') {
+            Color::Green.normal()
+        } else {
+            Style::new()
+        };
+        println!("{}", paint(style, line, color));
+    }
+}
+
+/// whether the output is colored, which is not the case with `--no-color`, when the
+/// `NO_COLOR` environment variable is set to anything but an empty string, or when the
+/// output is not a terminal, e.g. when it is piped to another command
+fn use_colors(no_color: bool, no_color_var: Option<&std::ffi::OsStr>, is_terminal: bool) -> bool {
+    !no_color && no_color_var.is_none_or(|value| value.is_empty()) && is_terminal
+}
+
+/// paint `text` with `style`, or leave it as plain text if `color` is not set
+fn paint(style: Style, text: &str, color: bool) -> String {
+    match color {
+        true => style.paint(text).to_string(),
+        false => text.to_string(),
+    }
+}
+
+/// check that the includes and excludes of `config` are valid globs
+fn check_patterns(config: &Config) -> Result<(), PatternError> {
+    for glob in config.includes.iter().chain(&config.excludes) {
+        Pattern::new(glob)?;
+    }
+    Ok(())
+}
+
+/// what `nufmt` does with the files it is given, and how it tells what happened to them
+struct FilesRun {
+    files: Vec<PathBuf>,
+    /// the directory the includes and excludes of `config` are relative to
+    root: PathBuf,
+    config: Config,
+    /// [`Mode::Check`] for `--check` and `--diff`, which leave the files untouched
+    mode: Mode,
+    search: SearchOptions,
+    /// print the changes of the files that would be reformatted as a diff, rather than
+    /// only their names
+    show_diff: bool,
+    output_format: OutputFormat,
+    quiet: bool,
+    color: bool,
+}
+
+/// format, or check, the files of a list of paths, see [`nu_formatter::format_paths_in`]
+///
+/// Returns `None` if one of the paths does not exist.
+fn format_paths(run: &FilesRun) -> Option<Vec<FileReport>> {
+    if let Some(missing) = run.files.iter().find(|file| !file.exists()) {
+        error!("Error: {} not found!", missing.display());
+        return None;
+    }
+    for excluded in nu_formatter::excluded_files(&run.root, &run.files, &run.config) {
+        eprintln!(
+            "Warning: {} is excluded by the configuration, but formatted as it is given explicitly",
+            excluded.display()
+        );
+    }
+
+    let diagnostics =
+        nu_formatter::format_paths_in(&run.root, &run.files, &run.config, &run.mode, &run.search);
+    Some(
+        diagnostics
+            .into_iter()
+            .map(|(path, diagnostic)| FileReport::new(path, diagnostic))
+            .collect(),
+    )
+}
+
+/// format a list of files, possibly one, and modify them inplace, or only report the ones
+/// that would be changed by the formatter in [`Mode::Check`]
+fn format_files(run: FilesRun) -> ExitCode {
+    let Some(reports) = format_paths(&run) else {
+        return ExitCode::Failure;
+    };
+    display_diagnostic_and_compute_exit_code(
+        reports,
+        run.mode == Mode::Check,
+        run.show_diff,
+        run.output_format,
+        run.quiet,
+        run.color,
+    )
+}
+
+/// count the files already formatted and the files that have been formatted
+///
+/// A count of zero files is left out.
+fn summary(reports: &[FileReport]) -> Vec<String> {
+    let count = |status: FileStatus| {
+        reports
+            .iter()
+            .filter(|report| report.status == status)
+            .count()
+    };
+
+    let mut lines = vec![];
+    match count(FileStatus::AlreadyFormatted) {
+        0 => {}
+        1 => lines.push("1 file already formatted".to_string()),
+        n => lines.push(format!("{n} files already formatted")),
+    }
+    match count(FileStatus::Reformatted) {
+        0 => {}
+        1 => lines.push("1 file was formatted".to_string()),
+        n => lines.push(format!("{n} files were formatted")),
     }
+    lines
+}
 
-    Ok(buf)
+/// count the files checked by status, in a line to print with the returned color
+///
+/// The color is green if all the files are formatted, yellow if some would be
+/// reformatted and red if some could not be formatted.
+fn check_summary(reports: &[FileReport]) -> (String, Color) {
+    let count = |status: &dyn Fn(&FileStatus) -> bool| {
+        reports
+            .iter()
+            .filter(|report| status(&report.status))
+            .count()
+    };
+    let formatted = count(&|status| *status == FileStatus::AlreadyFormatted);
+    let would_reformat = count(&|status| *status == FileStatus::WouldReformat);
+    let failed = count(&|status| matches!(status, FileStatus::Failure(_)));
+
+    let files = match reports.len() {
+        1 => "1 file".to_string(),
+        n => format!("{n} files"),
+    };
+    let line = format!(
+        "{files} checked: {formatted} already formatted, {would_reformat} would be reformatted, {failed} failed"
+    );
+    let color = if failed > 0 {
+        Color::Red
+    } else if would_reformat > 0 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    (line, color)
+}
+
+/// render the status of every file as a JSON array of `{path, status}` records
+///
+/// The failures also have the `reason` why the file could not be formatted.
+fn render_json_report(report: &[(PathBuf, FileStatus)]) -> String {
+    let entries: Vec<serde_json::Value> = report
+        .iter()
+        .map(|(path, status)| {
+            let mut entry = json!({
+                "path": path.display().to_string(),
+                "status": status.name(),
+            });
+            if let FileStatus::Failure(reason) = status {
+                entry["reason"] = json!(reason);
+            }
+            entry
+        })
+        .collect();
+    serde_json::Value::Array(entries).to_string()
 }
 
 /// Get the file extension
@@ -142,4 +987,279 @@ mod tests {
         use clap::CommandFactory;
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn read_nuon_config() {
+        let config = read_config(
+            Path::new("nufmt.nuon"),
+            b"# my config\n{indent: 2, line_length: 100}",
+        )
+        .unwrap();
+
+        assert_eq!(config.tab_spaces, 2);
+        assert_eq!(config.max_width, 100);
+    }
+
+    #[test]
+    fn read_toml_config() {
+        let config = read_config(
+            Path::new("nufmt.toml"),
+            b"# my config\nindent = 2\nline_length = 100",
+        )
+        .unwrap();
+
+        assert_eq!(config.tab_spaces, 2);
+        assert_eq!(config.max_width, 100);
+    }
+
+    #[test]
+    fn read_malformed_config() {
+        let err = read_config(Path::new("nufmt.toml"), b"indent = ").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidFormat(_)));
+
+        let err = read_config(Path::new("nufmt.nuon"), b"{indent: ").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidFormat(_)));
+
+        let err = read_config(Path::new("nufmt.toml"), b"indent = \"2\"").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidOptionType(..)));
+    }
+
+    #[test]
+    fn read_back_the_default_config() {
+        let nuon = Config::default().to_nuon();
+        let config = read_config(Path::new("nufmt.nuon"), nuon.as_bytes()).unwrap();
+
+        assert_eq!(format!("{config:?}"), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn read_back_every_option() {
+        let nuon = r#"{
+            indent: 2, line_length: 100, margin: 2, skip_garbage: true, line_ending: "crlf",
+            strip_bom: true, space_inside_braces: true, sort_imports: true, max_blank_lines: 3,
+            trailing_comma: "always", closure_inline_max_elements: 2, int_literal_case: "upper",
+            align_trailing_comments: true, pipe_placement: "trailing", split_semicolons: false,
+            always_expand_blocks: true, flag_value_style: "equals", align_match_arms: true,
+            empty_block_style: "spaced", record_colon_spacing: "spaced", align_record_values: true,
+            exclude: ["scripts/**"], include: ["*.nu", "lib/*"]
+        }"#;
+        let config = read_config(Path::new("nufmt.nuon"), nuon.as_bytes()).unwrap();
+        let read_back = read_config(Path::new("nufmt.nuon"), config.to_nuon().as_bytes()).unwrap();
+
+        // no option has its default value, one left out of the NUON would be read back with it
+        assert_ne!(format!("{config:?}"), format!("{:?}", Config::default()));
+        assert_eq!(format!("{read_back:?}"), format!("{config:?}"));
+    }
+
+    #[test]
+    fn point_to_unknown_option() {
+        let contents = b"{\n    indent: 2\n    identn: 4\n}";
+        let err = read_config(Path::new("nufmt.nuon"), contents).unwrap_err();
+
+        assert_eq!(
+            err,
+            ConfigError::UnknownOption("identn".to_string(), Some(28))
+        );
+        assert_eq!(
+            describe_config_error(contents, &err),
+            "unknown option `identn` at 3:5\n    identn: 4\n    ^^^^^^"
+        );
+
+        // there are no spans in TOML
+        let err = read_config(Path::new("nufmt.toml"), b"identn = 4").unwrap_err();
+        assert_eq!(
+            describe_config_error(b"identn = 4", &err),
+            "unknown option `identn`"
+        );
+    }
+
+    #[test]
+    fn find_closest_config() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+
+        assert_eq!(find_in_parent_dirs(&nested), None);
+
+        fs::write(root.path().join("nufmt.toml"), "").unwrap();
+        assert_eq!(
+            find_in_parent_dirs(&nested),
+            Some(root.path().join("nufmt.toml"))
+        );
+
+        fs::write(root.path().join("nufmt.nuon"), "").unwrap();
+        assert_eq!(
+            find_in_parent_dirs(&nested),
+            Some(root.path().join("nufmt.nuon"))
+        );
+
+        fs::write(root.path().join("a").join("nufmt.toml"), "").unwrap();
+        assert_eq!(
+            find_in_parent_dirs(&nested),
+            Some(root.path().join("a").join("nufmt.toml"))
+        );
+    }
+
+    #[test]
+    fn no_color_disables_the_colors() {
+        assert!(use_colors(false, None, true));
+        assert!(use_colors(false, Some(std::ffi::OsStr::new("")), true));
+        assert!(!use_colors(true, None, true));
+        assert!(!use_colors(false, Some(std::ffi::OsStr::new("1")), true));
+        // e.g. when the output is piped to another command
+        assert!(!use_colors(false, None, false));
+    }
+
+    #[test]
+    fn dry_run_is_an_alias_of_check() {
+        let cli = Cli::try_parse_from(["nufmt", "--dry-run", "foo.nu"]).unwrap();
+        assert!(cli.check);
+
+        let cli = Cli::try_parse_from(["nufmt", "--check", "foo.nu"]).unwrap();
+        assert!(cli.check);
+    }
+
+    #[test]
+    fn stdin_filepath_requires_stdin() {
+        assert!(Cli::try_parse_from(["nufmt", "--stdin-filepath", "foo.nu", "bar.nu"]).is_err());
+        assert!(Cli::try_parse_from(["nufmt", "--stdin-filepath", "foo.nu"]).is_err());
+        let args = ["nufmt", "--show-config", "--stdin-filepath", "foo.nu"];
+        assert!(Cli::try_parse_from(args).is_err());
+
+        let cli = Cli::try_parse_from(["nufmt", "--stdin", "--stdin-filepath", "foo.nu"]).unwrap();
+        assert_eq!(cli.stdin_filepath, Some(PathBuf::from("foo.nu")));
+    }
+
+    #[test]
+    fn range_requires_stdin() {
+        assert!(Cli::try_parse_from(["nufmt", "--range", "1:2", "foo.nu"]).is_err());
+        assert!(Cli::try_parse_from(["nufmt", "--range", "1:2", "--show-config"]).is_err());
+
+        let cli = Cli::try_parse_from(["nufmt", "--stdin", "--range", "1:2"]).unwrap();
+        assert_eq!(cli.range, Some(LineRange { start: 1, end: 2 }));
+    }
+
+    #[test]
+    fn parse_range_of_lines() {
+        assert_eq!(parse_line_range("2:3"), Ok(LineRange { start: 2, end: 3 }));
+        assert!(parse_line_range("2").is_err());
+        assert!(parse_line_range("0:3").is_err());
+        assert!(parse_line_range("3:2").is_err());
+        assert!(parse_line_range("a:b").is_err());
+    }
+
+    #[test]
+    fn check_conflicts_with_stdin() {
+        assert!(Cli::try_parse_from(["nufmt", "--check", "--stdin"]).is_err());
+        assert!(Cli::try_parse_from(["nufmt", "--dry-run", "--stdin"]).is_err());
+    }
+
+    #[test]
+    fn diff_shows_removed_and_added_lines() {
+        let diff = render_diff("foo.nu", "   0\n", "0\n");
+
+        assert!(diff.contains("--- foo.nu (original)"));
+        assert!(diff.contains("+++ foo.nu (formatted)"));
+        assert!(diff.contains("\n-   0\n"));
+        assert!(diff.contains("\n+0\n"));
+    }
+
+    #[test]
+    fn json_report_has_the_status_of_every_file() {
+        let report = vec![
+            (PathBuf::from("a.nu"), FileStatus::AlreadyFormatted),
+            (PathBuf::from("b.nu"), FileStatus::WouldReformat),
+            (
+                PathBuf::from("c.nu"),
+                FileStatus::Failure(failure_reason(
+                    b"let x = 1\n)",
+                    &FormatError::GarbageFound {
+                        span_start: 10,
+                        span_end: 11,
+                        snippet: ")".to_string(),
+                    },
+                )),
+            ),
+        ];
+
+        let json: serde_json::Value = serde_json::from_str(&render_json_report(&report)).unwrap();
+        assert_eq!(
+            json,
+            json!([
+                {"path": "a.nu", "status": "already_formatted"},
+                {"path": "b.nu", "status": "would_reformat"},
+                {"path": "c.nu", "status": "failure", "reason": "2:1: found invalid code: )"},
+            ])
+        );
+    }
+
+    #[test]
+    fn summary_counts_the_unchanged_and_the_formatted_files() {
+        let report = |name: &str, status: FileStatus| FileReport {
+            path: PathBuf::from(name),
+            status,
+            diff: None,
+            error: None,
+        };
+
+        let reports = [
+            report("a.nu", FileStatus::AlreadyFormatted),
+            report("b.nu", FileStatus::AlreadyFormatted),
+            report("c.nu", FileStatus::Reformatted),
+            report("d.nu", FileStatus::Failure("oops".to_string())),
+        ];
+        assert_eq!(
+            summary(&reports),
+            ["2 files already formatted", "1 file was formatted"]
+        );
+
+        let reports = [report("a.nu", FileStatus::WouldReformat)];
+        assert!(summary(&reports).is_empty());
+    }
+
+    #[test]
+    fn check_summary_counts_the_files_by_status() {
+        let report = |name: &str, status: FileStatus| FileReport {
+            path: PathBuf::from(name),
+            status,
+            diff: None,
+            error: None,
+        };
+
+        let reports = [
+            report("a.nu", FileStatus::AlreadyFormatted),
+            report("b.nu", FileStatus::WouldReformat),
+            report("c.nu", FileStatus::WouldReformat),
+            report("d.nu", FileStatus::Failure("oops".to_string())),
+        ];
+        assert_eq!(
+            check_summary(&reports),
+            (
+                "4 files checked: 1 already formatted, 2 would be reformatted, 1 failed"
+                    .to_string(),
+                Color::Red
+            )
+        );
+
+        let reports = [report("a.nu", FileStatus::WouldReformat)];
+        assert_eq!(
+            check_summary(&reports),
+            (
+                "1 file checked: 0 already formatted, 1 would be reformatted, 0 failed".to_string(),
+                Color::Yellow
+            )
+        );
+
+        let reports = [report("a.nu", FileStatus::AlreadyFormatted)];
+        assert_eq!(check_summary(&reports).1, Color::Green);
+    }
+
+    #[test]
+    fn error_location_is_one_based() {
+        let contents = b"let one = 1\nls | where size>10kb";
+
+        assert_eq!(line_and_column(contents, 0), (1, 1));
+        assert_eq!(line_and_column(contents, 12), (2, 1));
+        assert_eq!(line_and_column(contents, 17), (2, 6));
+    }
 }
@@ -0,0 +1,185 @@
+//! format the Nushell code blocks of Markdown documents, leaving the rest of them untouched
+use crate::{config::Config, format_file_contents, FormatError};
+
+/// the languages of the fenced code blocks that are formatted
+const NU_LANGUAGES: [&str; 2] = ["nu", "nushell"];
+
+/// format the ```` ```nu ```` and ```` ```nushell ```` fenced code blocks of a Markdown document
+///
+/// Everything else, the fences included, is kept byte for byte, as well as the blocks of other
+/// languages and a block that is never closed.
+/// The code of a fence indented, e.g. in a list item, is formatted without the indentation
+/// of the fence and indented back.
+/// The location of an error is the one in the document if the fence is not indented.
+pub fn format_markdown(contents: &[u8], config: &Config) -> Result<Vec<u8>, FormatError> {
+    let lines = split_lines(contents);
+    let mut out = Vec::with_capacity(contents.len());
+    let mut offset = 0;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        out.extend(line);
+        offset += line.len();
+
+        let Some(fence) = Fence::open(line) else {
+            continue;
+        };
+        let Some(length) = lines[index..]
+            .iter()
+            .position(|&line| fence.is_closed_by(line))
+        else {
+            // an unclosed block runs up to the end of the document, it is left as it is
+            continue;
+        };
+        let code = &lines[index..index + length];
+        let code_len = code.iter().map(|line| line.len()).sum::<usize>();
+        if fence.is_nu() {
+            out.extend(
+                format_block(code, fence.indentation, config)
+                    .map_err(|err| err.shifted_by(offset))?,
+            );
+        } else {
+            code.iter().for_each(|line| out.extend(*line));
+        }
+        // the closing fence is kept as it is, and can't open another block
+        let closing = lines[index + length];
+        out.extend(closing);
+        offset += code_len + closing.len();
+        index += length + 1;
+    }
+    Ok(out)
+}
+
+/// split `contents` into its lines, each one with its line ending
+fn split_lines(contents: &[u8]) -> Vec<&[u8]> {
+    contents.split_inclusive(|&byte| byte == b'\n').collect()
+}
+
+/// the line opening a fenced code block, e.g. ```` ```nu ````
+struct Fence {
+    /// the number of spaces before the fence, at most 3
+    indentation: usize,
+    /// either a backtick or a tilde
+    character: u8,
+    /// the number of times the character is repeated, at least 3
+    length: usize,
+    /// the first word after the fence, if any
+    language: String,
+}
+
+impl Fence {
+    fn open(line: &[u8]) -> Option<Self> {
+        let indentation = line.iter().take_while(|&&byte| byte == b' ').count();
+        if indentation > 3 {
+            return None;
+        }
+        let line = &line[indentation..];
+        let character = *line.first().filter(|&&byte| byte == b'`' || byte == b'~')?;
+        let length = line.iter().take_while(|&&byte| byte == character).count();
+        let info = String::from_utf8_lossy(&line[length..]);
+        // the info string of a backtick fence can't contain a backtick
+        if length < 3 || (character == b'`' && info.contains('`')) {
+            return None;
+        }
+        let language = info.split_whitespace().next().unwrap_or_default();
+        Some(Fence {
+            indentation,
+            character,
+            length,
+            language: language.to_lowercase(),
+        })
+    }
+
+    /// whether `line` is a fence closing this one, at least as long with nothing after it
+    fn is_closed_by(&self, line: &[u8]) -> bool {
+        let indentation = line.iter().take_while(|&&byte| byte == b' ').count();
+        let line = &line[indentation..];
+        let length = line
+            .iter()
+            .take_while(|&&byte| byte == self.character)
+            .count();
+        indentation <= 3
+            && length >= self.length
+            && line[length..].iter().all(u8::is_ascii_whitespace)
+    }
+
+    fn is_nu(&self) -> bool {
+        NU_LANGUAGES.contains(&self.language.as_str())
+    }
+}
+
+/// format the lines of code of a block whose fence is indented by `indentation` spaces
+///
+/// A block with nothing but whitespace is left as it is.
+fn format_block(
+    lines: &[&[u8]],
+    indentation: usize,
+    config: &Config,
+) -> Result<Vec<u8>, FormatError> {
+    let code: Vec<u8> = lines
+        .iter()
+        .flat_map(|line| {
+            let spaces = line
+                .iter()
+                .take(indentation)
+                .take_while(|&&byte| byte == b' ')
+                .count();
+            line[spaces..].iter().copied()
+        })
+        .collect();
+    if code.iter().all(u8::is_ascii_whitespace) {
+        return Ok(lines.concat());
+    }
+
+    let formatted = format_file_contents(&code, config)?;
+    let mut out = Vec::with_capacity(formatted.len());
+    for line in split_lines(&formatted) {
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            out.extend(std::iter::repeat_n(b' ', indentation));
+        }
+        out.extend(line);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(contents: &str) -> String {
+        let formatted = format_markdown(contents.as_bytes(), &Config::default()).unwrap();
+        String::from_utf8(formatted).unwrap()
+    }
+
+    #[test]
+    fn only_format_the_nu_blocks() {
+        let input = "# Title\n\nSome  prose   `let x  =  1`.\n\n```nu\nlet x  =  1\n```\n\n```sh\nlet x  =  1\n```\n\n~~~nushell title=\"a.nu\"\nls   |  get   name\n~~~\n";
+        let expected = "# Title\n\nSome  prose   `let x  =  1`.\n\n```nu\nlet x = 1\n```\n\n```sh\nlet x  =  1\n```\n\n~~~nushell title=\"a.nu\"\nls | get name\n~~~\n";
+        assert_eq!(format(input), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn format_an_indented_block() {
+        let input = "- a list\n\n  ```nu\n  let x  =  1\n\n  let y  =  2\n  ```\n";
+        let expected = "- a list\n\n  ```nu\n  let x = 1\n\n  let y = 2\n  ```\n";
+        assert_eq!(format(input), expected);
+    }
+
+    #[test]
+    fn leave_unclosed_and_empty_blocks_untouched() {
+        let input = "```nu\n\n```\n\n```nu\nlet x  =  1\n";
+        assert_eq!(format(input), input);
+    }
+
+    #[test]
+    fn locate_an_error_in_the_document() {
+        let input = "text\n\n```nu\nlet = 1\n```\n";
+        let err = format_markdown(input.as_bytes(), &Config::default()).unwrap_err();
+        match err {
+            FormatError::GarbageFound { span_start, .. } => assert!(span_start >= 10),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+}
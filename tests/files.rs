@@ -0,0 +1,353 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// run `nufmt` on the files of `dir`, from `dir` so no other configuration file is found
+fn format_dir(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nufmt"))
+        .args(args)
+        .arg(".")
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// a directory with a few badly formatted files, and one with invalid code
+fn project() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for index in 0..8 {
+        let contents = format!("let x{index}  =  [1  2  {index}]\n");
+        fs::write(dir.path().join(format!("file{index}.nu")), contents).unwrap();
+    }
+    fs::write(dir.path().join("invalid.nu"), "let = 1\n").unwrap();
+    dir
+}
+
+/// read the formatted files of `dir`, sorted by name
+fn read_files(dir: &Path) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn format_files_with_a_bounded_number_of_jobs() {
+    let default = project();
+    let sequential = project();
+
+    let default_output = format_dir(default.path(), &[]);
+    let sequential_output = format_dir(sequential.path(), &["--jobs", "1"]);
+
+    assert_eq!(default_output.status.code(), Some(1));
+    assert_eq!(sequential_output.status.code(), Some(1));
+    assert_eq!(read_files(default.path()), read_files(sequential.path()));
+    assert_eq!(
+        fs::read_to_string(sequential.path().join("file3.nu")).unwrap(),
+        "let x3 = [1 2 3]\n"
+    );
+}
+
+#[test]
+fn reject_zero_jobs() {
+    let dir = project();
+    let output = format_dir(dir.path(), &["--jobs", "0"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(read_files(dir.path()), read_files(project().path()));
+}
+
+#[test]
+fn report_the_files_sorted_by_path() {
+    let dir = project();
+    let output = format_dir(dir.path(), &["--check"]);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected: Vec<String> = (0..8)
+        .map(|index| {
+            let path = Path::new(".").join(format!("file{index}.nu"));
+            format!("Would reformat: {}", path.display())
+        })
+        .collect();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[..8], expected);
+    assert!(
+        lines[8].contains("9 files checked: 0 already formatted, 8 would be reformatted, 1 failed")
+    );
+    assert_eq!(lines.len(), 9);
+}
+
+#[test]
+fn no_color_prints_plain_text() {
+    let dir = project();
+    let check = |args: &[&str], no_color: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_nufmt"));
+        command
+            .args(["--check", "--diff"])
+            .args(args)
+            .arg(".")
+            .current_dir(dir.path())
+            .env_remove("NO_COLOR");
+        if let Some(value) = no_color {
+            command.env("NO_COLOR", value);
+        }
+        command.output().unwrap()
+    };
+    let is_plain = |output: Output| {
+        assert_eq!(output.status.code(), Some(1));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("+let x3 = [1 2 3]"));
+        !stdout.contains('\x1b') && !output.stderr.contains(&b'\x1b')
+    };
+
+    assert!(is_plain(check(&["--no-color"], None)));
+    assert!(is_plain(check(&[], Some("1"))));
+    // the output of the tests is piped, not written to a terminal
+    assert!(is_plain(check(&[], None)));
+}
+
+#[test]
+fn quiet_leaves_out_the_summary() {
+    let dir = project();
+    fs::remove_file(dir.path().join("invalid.nu")).unwrap();
+    fs::write(dir.path().join("file0.nu"), "let x0 = [1 2 0]\n").unwrap();
+
+    let output = format_dir(dir.path(), &[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 file already formatted\n7 files were formatted\n"
+    );
+
+    let output = format_dir(dir.path(), &["--quiet"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let output = format_dir(dir.path(), &["--check", "--quiet"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn format_the_files_listed_in_a_file() {
+    let dir = project();
+    fs::write(
+        dir.path().join("files.txt"),
+        "# the files to format\nfile1.nu\n\n  file5.nu  \n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nufmt"))
+        .args(["--files-from", "files.txt"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(read("file1.nu"), "let x1 = [1 2 1]\n");
+    assert_eq!(read("file5.nu"), "let x5 = [1 2 5]\n");
+    assert_eq!(read("file0.nu"), "let x0  =  [1  2  0]\n");
+}
+
+/// a directory with badly formatted files at several depths and the given configuration
+fn nested_project(config: &str) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for path in [
+        "main.nu",
+        "scripts/a.nu",
+        "scripts/sub/b.nu",
+        "lib/c.nu",
+        "lib/d.nu",
+    ] {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "let x  =  1\n").unwrap();
+    }
+    fs::write(dir.path().join("nufmt.nuon"), config).unwrap();
+    dir
+}
+
+/// whether the file at `path` in `dir` has been formatted
+fn is_formatted(dir: &Path, path: &str) -> bool {
+    fs::read_to_string(dir.join(path)).unwrap() == "let x = 1\n"
+}
+
+#[test]
+fn ignore_the_config_file() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("nufmt.nuon"), "{indent: 2}").unwrap();
+    let code = "def f [] {\n  # a comment\n  ls\n}\n";
+    fs::write(dir.path().join("main.nu"), code).unwrap();
+
+    let output = format_dir(dir.path(), &["--no-config"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(dir.path().join("main.nu")).unwrap(),
+        "def f [] {\n    # a comment\n    ls\n}\n"
+    );
+
+    fs::write(dir.path().join("main.nu"), code).unwrap();
+    assert!(format_dir(dir.path(), &[]).status.success());
+    assert_eq!(
+        fs::read_to_string(dir.path().join("main.nu")).unwrap(),
+        code
+    );
+}
+
+#[test]
+fn show_the_config_found() {
+    let dir = nested_project("{indent: 2}");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nufmt"))
+        .arg("--show-config")
+        .current_dir(dir.path().join("scripts/sub"))
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let config_path = dir.path().join("nufmt.nuon");
+    assert!(stdout.starts_with(&format!("# {}\n", config_path.display())));
+    assert!(stdout.contains("\n    indent: 2\n"));
+    assert!(!is_formatted(dir.path(), "scripts/sub/b.nu"));
+}
+
+#[test]
+fn exclude_a_directory() {
+    let dir = nested_project(r#"{exclude: ["scripts/**"]}"#);
+
+    let output = format_dir(dir.path(), &[]);
+
+    assert!(output.status.success());
+    assert!(is_formatted(dir.path(), "main.nu"));
+    assert!(is_formatted(dir.path(), "lib/c.nu"));
+    assert!(!is_formatted(dir.path(), "scripts/a.nu"));
+    assert!(!is_formatted(dir.path(), "scripts/sub/b.nu"));
+}
+
+#[test]
+fn exclude_a_nested_file() {
+    let dir = nested_project(r#"{exclude: ["**/d.nu"]}"#);
+
+    // the excludes are relative to the configuration file, not to the current directory
+    let output = format_dir(&dir.path().join("lib"), &[]);
+
+    assert!(output.status.success());
+    assert!(is_formatted(dir.path(), "lib/c.nu"));
+    assert!(!is_formatted(dir.path(), "lib/d.nu"));
+    assert!(!is_formatted(dir.path(), "main.nu"));
+}
+
+#[test]
+fn include_only_a_directory() {
+    let dir = nested_project(r#"{include: ["scripts/*"], exclude: ["**/b.nu"]}"#);
+
+    let output = format_dir(dir.path(), &[]);
+
+    assert!(output.status.success());
+    assert!(is_formatted(dir.path(), "scripts/a.nu"));
+    assert!(!is_formatted(dir.path(), "scripts/sub/b.nu"));
+    assert!(!is_formatted(dir.path(), "main.nu"));
+    assert!(!is_formatted(dir.path(), "lib/c.nu"));
+}
+
+#[test]
+fn warn_about_an_excluded_file_given_explicitly() {
+    let dir = nested_project(r#"{exclude: ["scripts/**"]}"#);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nufmt"))
+        .args(["scripts/a.nu", "lib"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "Warning: scripts/a.nu is excluded by the configuration, but formatted as it is given explicitly\n"
+    );
+    assert!(is_formatted(dir.path(), "scripts/a.nu"));
+}
+
+#[test]
+fn limit_the_depth_of_the_search() {
+    let dir = nested_project("{}");
+
+    let output = format_dir(dir.path(), &["--max-depth", "1"]);
+
+    assert!(output.status.success());
+    assert!(is_formatted(dir.path(), "main.nu"));
+    assert!(!is_formatted(dir.path(), "scripts/a.nu"));
+    assert!(!is_formatted(dir.path(), "lib/c.nu"));
+
+    format_dir(dir.path(), &["--max-depth", "2"]);
+    assert!(is_formatted(dir.path(), "scripts/a.nu"));
+    assert!(is_formatted(dir.path(), "lib/c.nu"));
+    assert!(!is_formatted(dir.path(), "scripts/sub/b.nu"));
+}
+
+#[test]
+fn format_the_nu_blocks_of_markdown_files() {
+    let dir = project();
+    let readme = "# Usage\n\n```nu\nlet a  =  1\n```\n\nRun  it   with:\n\n```nushell\nls   |  get   name\n```\n\n```sh\nnu  script.nu\n```\n";
+    fs::write(dir.path().join("README.md"), readme).unwrap();
+
+    format_dir(dir.path(), &[]);
+    assert_eq!(
+        fs::read_to_string(dir.path().join("README.md")).unwrap(),
+        readme
+    );
+
+    format_dir(dir.path(), &["--markdown"]);
+    assert_eq!(
+        fs::read_to_string(dir.path().join("README.md")).unwrap(),
+        "# Usage\n\n```nu\nlet a = 1\n```\n\nRun  it   with:\n\n```nushell\nls | get name\n```\n\n```sh\nnu  script.nu\n```\n"
+    );
+}
+
+#[test]
+fn backup_the_original_contents() {
+    let dir = project();
+
+    format_dir(dir.path(), &["--backup"]);
+
+    assert_eq!(
+        fs::read_to_string(dir.path().join("file3.nu")).unwrap(),
+        "let x3 = [1 2 3]\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("file3.nu.bak")).unwrap(),
+        "let x3  =  [1  2  3]\n"
+    );
+    // the invalid file is left untouched, without a copy
+    assert!(!dir.path().join("invalid.nu.bak").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn keep_the_executable_bit_of_a_script() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("script.nu");
+    fs::write(&script, "#!/usr/bin/env nu\nlet x  =  1\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    format_dir(dir.path(), &[]);
+
+    assert_eq!(
+        fs::read_to_string(&script).unwrap(),
+        "#!/usr/bin/env nu\n\nlet x = 1\n"
+    );
+    let mode = fs::metadata(&script).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o755);
+}
@@ -0,0 +1,105 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// run `nufmt --stdin` outside of the repository, so no configuration file is found
+fn format_stdin(input: &[u8]) -> Output {
+    format_stdin_with_args(input, &[])
+}
+
+fn format_stdin_with_args(input: &[u8], args: &[&OsStr]) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nufmt"))
+        .arg("--stdin")
+        .args(args)
+        .current_dir(dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn format_crlf_content_from_stdin() {
+    let output = format_stdin(b"# comment\r\nlet x  =  1\r\n");
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"# comment\r\nlet x = 1\r\n");
+}
+
+#[test]
+fn reject_non_utf8_stdin() {
+    let output = format_stdin(b"let x = \xff");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: "));
+}
+
+#[test]
+fn find_config_from_stdin_filepath() {
+    let project = tempfile::tempdir().unwrap();
+    fs::write(project.path().join("nufmt.nuon"), "{line_ending: \"crlf\"}").unwrap();
+    let file = project.path().join("scripts").join("foo.nu");
+
+    let output = format_stdin_with_args(
+        b"let x  =  1\n",
+        &["--stdin-filepath".as_ref(), file.as_os_str()],
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"let x = 1\r\n");
+
+    let output = format_stdin(b"let x  =  1\n");
+    assert_eq!(output.stdout, b"let x = 1\n");
+}
+
+#[test]
+fn echo_stdin_of_an_excluded_filepath() {
+    let project = tempfile::tempdir().unwrap();
+    fs::write(project.path().join("nufmt.nuon"), "{exclude: [\"vendor\"]}").unwrap();
+    let input = b"let x  =  1\n";
+
+    let excluded = project.path().join("vendor").join("foo.nu");
+    let output =
+        format_stdin_with_args(input, &["--stdin-filepath".as_ref(), excluded.as_os_str()]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, input);
+
+    let output = format_stdin_with_args(
+        input,
+        &[
+            "--diff".as_ref(),
+            "--stdin-filepath".as_ref(),
+            excluded.as_os_str(),
+        ],
+    );
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let included = project.path().join("scripts").join("foo.nu");
+    let output =
+        format_stdin_with_args(input, &["--stdin-filepath".as_ref(), included.as_os_str()]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"let x = 1\n");
+}
+
+#[test]
+fn read_inline_config() {
+    // the comment keeps the body of `f` on several lines
+    let input = b"def f [] {\n    # list\n    ls\n}\n";
+
+    let output =
+        format_stdin_with_args(input, &["--config-inline".as_ref(), "{indent: 2}".as_ref()]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"def f [] {\n  # list\n  ls\n}\n");
+
+    let output =
+        format_stdin_with_args(input, &["--config-inline".as_ref(), "{identn: 2}".as_ref()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown option `identn`"));
+}